@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS artists (
+ id INTEGER PRIMARY KEY AUTOINCREMENT,
+ name TEXT NOT NULL COLLATE NOCASE
+);
+CREATE TABLE IF NOT EXISTS songs (
+ id INTEGER PRIMARY KEY AUTOINCREMENT,
+ title TEXT NOT NULL COLLATE NOCASE,
+ artist_id INTEGER,
+ album TEXT COLLATE NOCASE,
+ genre TEXT COLLATE NOCASE,
+ duration INTEGER,
+ FOREIGN KEY (artist_id)
+    REFERENCES artists (id)
+    ON UPDATE CASCADE
+    ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS listening_times (
+ id INTEGER PRIMARY KEY AUTOINCREMENT,
+ date DATE,
+ song_id INTEGER,
+ playback_time INTEGER NOT NULL,
+ FOREIGN KEY (song_id)
+    REFERENCES songs (id)
+    ON UPDATE SET NULL
+    ON DELETE SET NULL
+);
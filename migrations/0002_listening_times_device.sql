@@ -0,0 +1 @@
+ALTER TABLE listening_times ADD COLUMN device TEXT COLLATE NOCASE;
@@ -0,0 +1,47 @@
+-- Older versions used a check-then-insert pattern that could create duplicate rows,
+-- so merge them into the row with the lowest id before adding the unique indexes.
+UPDATE songs
+SET artist_id = (
+ SELECT MIN(other.id)
+ FROM artists AS this
+ INNER JOIN artists AS other
+ ON other.name = this.name
+ WHERE this.id = songs.artist_id
+)
+WHERE artist_id IN (SELECT id FROM artists);
+DELETE FROM artists
+WHERE id NOT IN (SELECT MIN(id) FROM artists GROUP BY name);
+
+UPDATE listening_times
+SET song_id = (
+ SELECT MIN(other.id)
+ FROM songs AS this
+ INNER JOIN songs AS other
+ ON other.title = this.title AND other.artist_id IS this.artist_id
+ WHERE this.id = listening_times.song_id
+)
+WHERE song_id IN (SELECT id FROM songs);
+DELETE FROM songs
+WHERE id NOT IN (SELECT MIN(id) FROM songs GROUP BY title, artist_id);
+
+UPDATE listening_times
+SET playback_time = (
+ SELECT SUM(other.playback_time)
+ FROM listening_times AS other
+ WHERE other.date = listening_times.date
+  AND other.song_id IS listening_times.song_id
+  AND other.device IS listening_times.device
+)
+WHERE id IN (
+ SELECT MIN(id) FROM listening_times GROUP BY date, song_id, device HAVING COUNT(*) > 1
+);
+DELETE FROM listening_times
+WHERE id NOT IN (SELECT MIN(id) FROM listening_times GROUP BY date, song_id, device);
+
+CREATE UNIQUE INDEX artists_name ON artists (name);
+CREATE UNIQUE INDEX songs_title_artist_id ON songs (title, artist_id);
+CREATE UNIQUE INDEX listening_times_date_song_id_device ON listening_times (date, song_id, device);
+
+-- Covering indexes for the joins done by print
+CREATE INDEX songs_artist_id ON songs (artist_id);
+CREATE INDEX listening_times_song_id_covering ON listening_times (song_id, date, device, playback_time);
@@ -0,0 +1,61 @@
+-- Listening times summed up per day, month and year so that stats don't have to aggregate
+-- the whole history every time. Rows are keyed by the first day of their period.
+CREATE TABLE rollup_days (
+ date DATE NOT NULL,
+ song_id INTEGER NOT NULL,
+ device TEXT COLLATE NOCASE,
+ playback_time INTEGER NOT NULL,
+ first_listened DATE NOT NULL,
+ last_listened DATE NOT NULL,
+ FOREIGN KEY (song_id)
+    REFERENCES songs (id)
+    ON UPDATE CASCADE
+    ON DELETE CASCADE
+);
+CREATE TABLE rollup_months (
+ date DATE NOT NULL,
+ song_id INTEGER NOT NULL,
+ device TEXT COLLATE NOCASE,
+ playback_time INTEGER NOT NULL,
+ first_listened DATE NOT NULL,
+ last_listened DATE NOT NULL,
+ FOREIGN KEY (song_id)
+    REFERENCES songs (id)
+    ON UPDATE CASCADE
+    ON DELETE CASCADE
+);
+CREATE TABLE rollup_years (
+ date DATE NOT NULL,
+ song_id INTEGER NOT NULL,
+ device TEXT COLLATE NOCASE,
+ playback_time INTEGER NOT NULL,
+ first_listened DATE NOT NULL,
+ last_listened DATE NOT NULL,
+ FOREIGN KEY (song_id)
+    REFERENCES songs (id)
+    ON UPDATE CASCADE
+    ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX rollup_days_date_song_id_device ON rollup_days (date, song_id, device);
+CREATE UNIQUE INDEX rollup_months_date_song_id_device ON rollup_months (date, song_id, device);
+CREATE UNIQUE INDEX rollup_years_date_song_id_device ON rollup_years (date, song_id, device);
+CREATE INDEX rollup_days_song_id ON rollup_days (song_id);
+CREATE INDEX rollup_months_song_id ON rollup_months (song_id);
+CREATE INDEX rollup_years_song_id ON rollup_years (song_id);
+
+INSERT INTO rollup_days (date, song_id, device, playback_time, first_listened, last_listened)
+SELECT date, song_id, device, SUM(playback_time), MIN(date), MAX(date)
+FROM listening_times
+WHERE date IS NOT NULL AND song_id IN (SELECT id FROM songs)
+GROUP BY date, song_id, device;
+INSERT INTO rollup_months (date, song_id, device, playback_time, first_listened, last_listened)
+SELECT date(date, 'start of month'), song_id, device, SUM(playback_time), MIN(date), MAX(date)
+FROM listening_times
+WHERE date IS NOT NULL AND song_id IN (SELECT id FROM songs)
+GROUP BY date(date, 'start of month'), song_id, device;
+INSERT INTO rollup_years (date, song_id, device, playback_time, first_listened, last_listened)
+SELECT date(date, 'start of year'), song_id, device, SUM(playback_time), MIN(date), MAX(date)
+FROM listening_times
+WHERE date IS NOT NULL AND song_id IN (SELECT id FROM songs)
+GROUP BY date(date, 'start of year'), song_id, device;
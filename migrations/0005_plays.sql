@@ -0,0 +1,13 @@
+-- Count how often songs were started and how often they were skipped before reaching half of
+-- their duration. Older rows have no way of knowing, so they start at 0.
+ALTER TABLE listening_times ADD COLUMN plays INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE listening_times ADD COLUMN skips INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rollup_days ADD COLUMN plays INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rollup_days ADD COLUMN skips INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rollup_months ADD COLUMN plays INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rollup_months ADD COLUMN skips INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rollup_years ADD COLUMN plays INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rollup_years ADD COLUMN skips INTEGER NOT NULL DEFAULT 0;
+
+DROP INDEX listening_times_song_id_covering;
+CREATE INDEX listening_times_song_id_covering ON listening_times (song_id, date, device, playback_time, plays, skips);
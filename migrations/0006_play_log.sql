@@ -0,0 +1,19 @@
+-- Every play recorded by the daemon along with the local time it started at, so that stats can
+-- be split by the time of day. Each row is a single play, plays is only there so that the rows
+-- sum up like listening_times.
+CREATE TABLE plays (
+ id INTEGER PRIMARY KEY AUTOINCREMENT,
+ date DATE NOT NULL,
+ started_at DATETIME NOT NULL,
+ song_id INTEGER NOT NULL,
+ device TEXT COLLATE NOCASE,
+ playback_time INTEGER NOT NULL DEFAULT 0,
+ plays INTEGER NOT NULL DEFAULT 1,
+ skips INTEGER NOT NULL DEFAULT 0,
+ FOREIGN KEY (song_id)
+    REFERENCES songs (id)
+    ON UPDATE CASCADE
+    ON DELETE CASCADE
+);
+
+CREATE INDEX plays_song_id ON plays (song_id);
@@ -0,0 +1,33 @@
+CREATE TABLE IF NOT EXISTS artists (
+ id BIGSERIAL PRIMARY KEY,
+ name TEXT NOT NULL UNIQUE
+);
+CREATE TABLE IF NOT EXISTS songs (
+ id BIGSERIAL PRIMARY KEY,
+ title TEXT NOT NULL,
+ artist_id BIGINT,
+ album TEXT,
+ genre TEXT,
+ duration INTEGER,
+ UNIQUE (title, artist_id),
+ FOREIGN KEY (artist_id)
+    REFERENCES artists (id)
+    ON UPDATE CASCADE
+    ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS listening_times (
+ id BIGSERIAL PRIMARY KEY,
+ date DATE,
+ song_id BIGINT,
+ playback_time INTEGER NOT NULL,
+ device TEXT,
+ UNIQUE (date, song_id, device),
+ FOREIGN KEY (song_id)
+    REFERENCES songs (id)
+    ON UPDATE SET NULL
+    ON DELETE SET NULL
+);
+
+-- Covering indexes for the joins done by print
+CREATE INDEX songs_artist_id ON songs (artist_id);
+CREATE INDEX listening_times_song_id_covering ON listening_times (song_id, date, device, playback_time);
@@ -0,0 +1,239 @@
+//! Bar charts and sparklines for `print --chart`
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    structs::{DataRow, DurationFormat, TimeGroup, WeekStyle},
+    table::truncate,
+};
+
+/// Labels never take up more than this part of the width
+const MAX_LABEL_SHARE: f64 = 0.4;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const ASCII_SPARKS: [char; 8] = ['_', '.', ',', '-', '=', '+', '*', '#'];
+/// Blocks filling one to seven eighths of a character from the left
+const EIGHTHS: [char; 7] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Unicode,
+    Ascii,
+}
+
+impl Charset {
+    /// Unicode unless the locale says the terminal can't show it
+    pub fn detect() -> Self {
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(|x| std::env::var(x).ok())
+            .find(|x| !x.is_empty())
+            .unwrap_or_default()
+            .to_lowercase();
+        match locale.contains("utf-8") || locale.contains("utf8") {
+            true => Charset::Unicode,
+            false => Charset::Ascii,
+        }
+    }
+
+    /// Dash between the parts of a label
+    fn dash(self) -> &'static str {
+        match self {
+            Charset::Unicode => " – ",
+            Charset::Ascii => " - ",
+        }
+    }
+
+    /// Bar `width` characters long at most, filled up to `fraction`
+    fn bar(self, fraction: f64, width: usize) -> String {
+        let eighths = (fraction * width as f64 * 8.0).round() as usize;
+        match self {
+            Charset::Unicode => {
+                let mut bar = "█".repeat(eighths / 8);
+                match eighths % 8 {
+                    0 => {}
+                    partial => bar.push(EIGHTHS[partial - 1]),
+                }
+                bar
+            }
+            Charset::Ascii => "#".repeat((eighths + 4) / 8),
+        }
+    }
+
+    /// Character for a value that reaches `fraction` of the highest one
+    fn spark(self, fraction: f64) -> char {
+        let sparks = match self {
+            Charset::Unicode => &SPARKS,
+            Charset::Ascii => &ASCII_SPARKS,
+        };
+        match fraction {
+            x if x <= 0.0 => ' ',
+            x => sparks[((x * sparks.len() as f64).ceil() as usize).clamp(1, sparks.len()) - 1],
+        }
+    }
+}
+
+/// Draw one bar per row, labelled with its time group and the fields it was grouped by
+pub fn write_bars(
+    out: &mut impl Write,
+    rows: &[DataRow],
+    with_dates: bool,
+    width: usize,
+    durations: DurationFormat,
+    charset: Charset,
+) -> io::Result<()> {
+    let labels: Vec<String> = rows
+        .iter()
+        .map(|row| match with_dates {
+            true => format!("{}  {}", row.date, entry_label(row, charset)),
+            false => entry_label(row, charset),
+        })
+        .collect();
+    let values: Vec<String> = rows
+        .iter()
+        .map(|row| durations.format(row.time.into()))
+        .collect();
+    let max = rows.iter().map(|x| x.time).max().unwrap_or_default();
+    let label_width = label_width(&labels, width);
+    let value_width = values.iter().map(|x| x.width()).max().unwrap_or_default();
+    let bar_width = width.saturating_sub(label_width + value_width + 4).max(1);
+
+    for ((row, label), value) in rows.iter().zip(&labels).zip(&values) {
+        let bar = charset.bar(f64::from(row.time) / f64::from(max.max(1)), bar_width);
+        writeln!(
+            out,
+            "{}  {value:>value_width$}  {bar}",
+            pad(&truncate(label, label_width), label_width),
+        )?;
+    }
+    Ok(())
+}
+
+/// Draw a line for each of the `entries` most listened entries, plus one for all of them,
+/// showing how their listening time changed over the time groups. `rows` have to be ordered
+/// by time group and still have the keys of their time buckets as dates.
+pub fn write_sparklines(
+    out: &mut impl Write,
+    rows: &[DataRow],
+    (group, weeks): (&TimeGroup, WeekStyle),
+    entries: usize,
+    width: usize,
+    durations: DurationFormat,
+    charset: Charset,
+) -> io::Result<()> {
+    let (Some(first), Some(last)) = (rows.first(), rows.last()) else {
+        return Ok(());
+    };
+    // Every time group from the first to the last one, including the ones nothing was
+    // listened in so that the lines don't squeeze time together
+    let mut periods = vec![first.date.clone()];
+    while periods.last() != Some(&last.date) {
+        match periods.last().and_then(|x| group.next_key(x)) {
+            Some(next) if next <= last.date => periods.push(next),
+            // Keys that can't be counted up fall back to the time groups that have rows
+            _ => {
+                periods = rows.iter().map(|x| x.date.clone()).collect();
+                periods.dedup();
+                break;
+            }
+        }
+    }
+    let positions: HashMap<&str, usize> = periods
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.as_str(), i))
+        .collect();
+    let mut all = vec![0; periods.len()];
+    let mut lines: Vec<(String, Vec<u64>)> = Vec::new();
+    for row in rows {
+        let Some(&position) = positions.get(row.date.as_str()) else {
+            continue;
+        };
+        all[position] += u64::from(row.time);
+        let label = entry_label(row, charset);
+        let line = match lines.iter().position(|(x, _)| *x == label) {
+            Some(i) => &mut lines[i].1,
+            None => {
+                lines.push((label, vec![0; periods.len()]));
+                &mut lines.last_mut().expect("Just pushed").1
+            }
+        };
+        line[position] += u64::from(row.time);
+    }
+    lines.sort_by_key(|(_, line)| std::cmp::Reverse(line.iter().sum::<u64>()));
+    lines.truncate(entries);
+    lines.insert(0, ("All".to_owned(), all));
+
+    let labels: Vec<String> = lines.iter().map(|(x, _)| x.clone()).collect();
+    let totals: Vec<String> = lines
+        .iter()
+        .map(|(_, line)| durations.format(line.iter().sum()))
+        .collect();
+    let label_width = label_width(&labels, width);
+    let total_width = totals.iter().map(|x| x.width()).max().unwrap_or_default();
+    let spark_width = width.saturating_sub(label_width + total_width + 4).max(1);
+    // Add up neighbouring time groups when there are more of them than room
+    let per_spark = periods.len().div_ceil(spark_width).max(1);
+
+    for ((label, line), total) in labels.iter().zip(&lines).zip(&totals) {
+        let sums: Vec<u64> = line.1.chunks(per_spark).map(|x| x.iter().sum()).collect();
+        let max = sums.iter().copied().max().unwrap_or_default().max(1);
+        let sparkline: String = sums
+            .iter()
+            .map(|x| charset.spark(*x as f64 / max as f64))
+            .collect();
+        writeln!(
+            out,
+            "{}  {total:>total_width$}  {sparkline}",
+            pad(&truncate(label, label_width), label_width),
+        )?;
+    }
+    let label = |key: &String| group.label(key, weeks, true).unwrap_or(key.clone());
+    writeln!(
+        out,
+        "{}  {}{}{}",
+        " ".repeat(label_width + total_width + 2),
+        label(&first.date),
+        charset.dash(),
+        label(&last.date)
+    )?;
+    Ok(())
+}
+
+/// Name of the entry, like Artist – Title. Grouping by title or album also returns the album
+/// and genre, which would only make the label longer.
+pub(crate) fn entry_label(row: &DataRow, charset: Charset) -> String {
+    let name = match (&row.title, &row.album) {
+        (Some(title), _) => Some(title),
+        (None, Some(album)) => Some(album),
+        (None, None) => row.genre.as_ref(),
+    };
+    let label = [row.artist.as_ref(), name, row.device.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|x| x.as_str())
+        .collect::<Vec<_>>()
+        .join(charset.dash());
+    // Songs without an album or genre are grouped together
+    match label.is_empty() {
+        true => "Unknown".to_owned(),
+        false => label,
+    }
+}
+
+fn label_width(labels: &[String], width: usize) -> usize {
+    labels
+        .iter()
+        .map(|x| x.width())
+        .max()
+        .unwrap_or_default()
+        .min((width as f64 * MAX_LABEL_SHARE) as usize)
+}
+
+fn pad(text: &str, width: usize) -> String {
+    format!("{text}{}", " ".repeat(width.saturating_sub(text.width())))
+}
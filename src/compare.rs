@@ -0,0 +1,142 @@
+//! Side by side report of two periods for the `compare` subcommand
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+};
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    chart::{entry_label, Charset},
+    structs::{DataRow, DurationFormat},
+};
+
+const SEPARATOR: &str = "  ";
+
+/// Fields that tell entries apart, whichever of them were grouped by
+type EntryKey<'a> = (
+    Option<u32>,
+    Option<u32>,
+    Option<&'a str>,
+    Option<&'a str>,
+    Option<&'a str>,
+    Option<&'a str>,
+);
+
+fn entry_key(row: &DataRow) -> EntryKey<'_> {
+    (
+        row.artist_id,
+        row.song_id,
+        row.title.as_deref(),
+        row.album.as_deref(),
+        row.genre.as_deref(),
+        row.device.as_deref(),
+    )
+}
+
+/// Write the `limit` highest ranked entries of `current` next to where they were in
+/// `previous`, followed by the entries that were among the `limit` highest ranked in `previous`
+/// but aren't anymore
+pub fn write_comparison(
+    out: &mut impl Write,
+    current: &[DataRow],
+    previous: &[DataRow],
+    limit: usize,
+    durations: DurationFormat,
+    charset: Charset,
+) -> io::Result<()> {
+    let before: HashMap<_, _> = previous.iter().map(|x| (entry_key(x), x)).collect();
+    let now: HashMap<_, _> = current.iter().map(|x| (entry_key(x), x)).collect();
+    let mut current: Vec<&DataRow> = current.iter().collect();
+    current.sort_by_key(|x| x.rank);
+    current.truncate(limit);
+    let shown: HashSet<_> = current.iter().map(|x| entry_key(x)).collect();
+
+    let mut rows =
+        vec![["Rank", "Move", "Entry", "Now", "Before", "Change", "Was"].map(str::to_owned)];
+    for row in &current {
+        let previous = before.get(&entry_key(row));
+        rows.push([
+            row.rank.to_string(),
+            movement(row.rank, previous.map(|x| x.rank), charset),
+            entry_label(row, charset),
+            durations.format(row.time.into()),
+            previous.map_or_else(String::new, |x| durations.format(x.time.into())),
+            previous.map_or_else(String::new, |x| change(x.time, row.time)),
+            previous.map_or_else(String::new, |x| x.rank.to_string()),
+        ]);
+    }
+    match current.is_empty() {
+        true => writeln!(out, "Nothing was listened to")?,
+        false => write_aligned(out, &rows, &[true, true, false, true, true, true, true])?,
+    }
+
+    let mut dropped: Vec<&DataRow> = previous
+        .iter()
+        .filter(|x| (x.rank as usize) <= limit && !shown.contains(&entry_key(x)))
+        .collect();
+    if dropped.is_empty() {
+        return Ok(());
+    }
+    dropped.sort_by_key(|x| x.rank);
+    let mut rows = vec![["Was", "Entry", "Before", "Now", "Rank"].map(str::to_owned)];
+    for row in dropped {
+        let current = now.get(&entry_key(row));
+        rows.push([
+            row.rank.to_string(),
+            entry_label(row, charset),
+            durations.format(row.time.into()),
+            current.map_or_else(String::new, |x| durations.format(x.time.into())),
+            current.map_or_else(String::new, |x| x.rank.to_string()),
+        ]);
+    }
+    writeln!(out, "\nDropped out")?;
+    write_aligned(out, &rows, &[true, false, true, true, true])
+}
+
+/// How many places the entry went up or down since it was at `previous`
+fn movement(rank: u32, previous: Option<u32>, charset: Charset) -> String {
+    let (up, down) = match charset {
+        Charset::Unicode => ("↑", "↓"),
+        Charset::Ascii => ("+", "-"),
+    };
+    match previous {
+        None => "new".to_owned(),
+        Some(previous) if previous > rank => format!("{up}{}", previous - rank),
+        Some(previous) if previous < rank => format!("{down}{}", rank - previous),
+        Some(_) => "=".to_owned(),
+    }
+}
+
+/// Change in listening time as a percentage, like +12%
+fn change(before: u32, now: u32) -> String {
+    let change = (f64::from(now) - f64::from(before)) / f64::from(before.max(1)) * 100.0;
+    format!("{change:+.0}%")
+}
+
+/// Write `rows` with every column padded to its widest cell, the first row being the header
+fn write_aligned<const N: usize>(
+    out: &mut impl Write,
+    rows: &[[String; N]],
+    right_aligned: &[bool; N],
+) -> io::Result<()> {
+    let widths: Vec<usize> = (0..N)
+        .map(|i| rows.iter().map(|x| x[i].width()).max().unwrap_or_default())
+        .collect();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                let padding = " ".repeat(widths[i] - cell.width());
+                match right_aligned[i] {
+                    true => format!("{padding}{cell}"),
+                    false => format!("{cell}{padding}"),
+                }
+            })
+            .collect();
+        writeln!(out, "{}", cells.join(SEPARATOR).trim_end())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,638 @@
+use std::{
+    fs::{create_dir_all, read_dir, remove_file},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use log::{error, info};
+use regex::Regex;
+use sqlx::SqlitePool;
+
+use crate::storage::sql::Rollup;
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every schema change ever made to the database, in the order they have to be applied.
+/// Never edit a migration that has been released, add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create artists, songs and listening_times tables",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "add device column to listening_times",
+        sql: include_str!("../migrations/0002_listening_times_device.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "merge duplicate rows and add unique and covering indexes",
+        sql: include_str!("../migrations/0003_unique_indexes.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "add daily, monthly and yearly rollups of listening times",
+        sql: include_str!("../migrations/0004_rollups.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "count plays and skips",
+        sql: include_str!("../migrations/0005_plays.sql"),
+    },
+    Migration {
+        version: 6,
+        description: "log every play with the time it started",
+        sql: include_str!("../migrations/0006_play_log.sql"),
+    },
+];
+
+/// Return the migrations that have not been applied to the database yet
+pub async fn pending_migrations(pool: &SqlitePool) -> Result<Vec<&'static Migration>> {
+    let version = schema_version(pool).await?;
+    Ok(MIGRATIONS.iter().filter(|x| x.version > version).collect())
+}
+
+/// Bring the database schema up to date by applying all pending migrations
+pub async fn migrate(pool: &SqlitePool) -> Result<()> {
+    if !table_exists(pool, "schema_version").await? {
+        // Databases created before versioned migrations already contain some of the schema,
+        // so record the migrations that were effectively applied by older versions
+        let legacy_version = legacy_schema_version(pool).await?;
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+CREATE TABLE schema_version (
+ version INTEGER PRIMARY KEY,
+ description TEXT NOT NULL,
+ applied_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);"#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        for migration in MIGRATIONS.iter().filter(|x| x.version <= legacy_version) {
+            sqlx::query("INSERT INTO schema_version (version, description) VALUES ($1, $2)")
+                .bind(migration.version)
+                .bind(migration.description)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+    }
+
+    for migration in pending_migrations(pool).await? {
+        info!(
+            "Applying database migration {:04}: {}",
+            migration.version, migration.description
+        );
+        let mut tx = pool.begin().await?;
+        sqlx::query(migration.sql).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_version (version, description) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.description)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+/// Get the version of the latest migration applied to the database
+pub async fn schema_version(pool: &SqlitePool) -> Result<i64> {
+    if !table_exists(pool, "schema_version").await? {
+        return legacy_schema_version(pool).await;
+    }
+    Ok(
+        sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(version) FROM schema_version")
+            .fetch_one(pool)
+            .await?
+            .unwrap_or_default(),
+    )
+}
+
+/// Work out which schema version a database that predates the `schema_version` table is at
+async fn legacy_schema_version(pool: &SqlitePool) -> Result<i64> {
+    if !table_exists(pool, "listening_times").await? {
+        return Ok(0);
+    }
+    let has_device =
+        sqlx::query("SELECT 1 FROM pragma_table_info('listening_times') WHERE name = 'device'")
+            .fetch_optional(pool)
+            .await?
+            .is_some();
+    Ok(if has_device { 2 } else { 1 })
+}
+
+async fn table_exists(pool: &SqlitePool, name: &str) -> Result<bool> {
+    Ok(
+        sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = $1")
+            .bind(name)
+            .fetch_optional(pool)
+            .await?
+            .is_some(),
+    )
+}
+
+/// Print the pending migrations, or apply them if `dry_run` isn't set
+pub async fn run_migrations(pool: &SqlitePool, dry_run: bool) -> Result<()> {
+    let pending = pending_migrations(pool).await?;
+    if pending.is_empty() {
+        println!(
+            "Database schema is up to date (version {})",
+            schema_version(pool).await?
+        );
+        return Ok(());
+    }
+    if dry_run {
+        println!("Pending migrations:");
+        for migration in pending {
+            println!("{:04}: {}", migration.version, migration.description);
+        }
+    } else {
+        migrate(pool).await?;
+        println!(
+            "Database schema migrated to version {}",
+            schema_version(pool).await?
+        );
+    }
+    Ok(())
+}
+
+const ORPHANED_LISTENING_TIMES: &str = "song_id IS NULL OR song_id NOT IN (SELECT id FROM songs)";
+const SONGS_WITHOUT_ARTISTS: &str =
+    "artist_id IS NULL OR artist_id NOT IN (SELECT id FROM artists)";
+const SONGS_WITHOUT_TITLES: &str = "title IS NULL OR TRIM(title) = ''";
+const ROLLUP_COLUMNS: &str =
+    "date, song_id, device, playback_time, plays, skips, first_listened, last_listened";
+
+/// Name given to the artist or title of songs that lost theirs
+const UNKNOWN: &str = "Unknown";
+
+/// Report rows that have lost their references or required fields and rollups that disagree
+/// with the listening times. With `repair` songs are given an Unknown artist or title so their
+/// listening history is kept, listening times that belong to no song at all are removed and
+/// the rollups are rebuilt. While migrations are pending only the listening times are checked.
+pub async fn check(pool: &SqlitePool, repair: bool) -> Result<()> {
+    // Checking shouldn't change the schema behind the user's back
+    let pending = pending_migrations(pool).await?;
+    if !pending.is_empty() {
+        println!("The database schema is out of date, pending migrations:");
+        for migration in pending {
+            println!("{:04}: {}", migration.version, migration.description);
+        }
+        // Listening times of deleted songs can stop the migrations, so they can be removed
+        // from any schema
+        if table_exists(pool, "listening_times").await? {
+            let orphaned = sqlx::query_scalar::<_, i64>(&format!(
+                "SELECT COUNT(*) FROM listening_times WHERE {ORPHANED_LISTENING_TIMES}"
+            ))
+            .fetch_one(pool)
+            .await?;
+            println!("Orphaned listening times: {orphaned}");
+            if orphaned > 0 && repair {
+                let removed = sqlx::query(&format!(
+                    "DELETE FROM listening_times WHERE {ORPHANED_LISTENING_TIMES}"
+                ))
+                .execute(pool)
+                .await?
+                .rows_affected();
+                println!("Removed {removed} listening times");
+            } else if orphaned > 0 {
+                println!(
+                    "Run `{} db check --repair` to remove listening times without a song",
+                    env!("CARGO_PKG_NAME")
+                );
+            }
+        }
+        println!(
+            "Run `{} db migrate` before checking the rest of the database",
+            env!("CARGO_PKG_NAME")
+        );
+        return Ok(());
+    }
+    let checks = [
+        (
+            "Orphaned listening times",
+            "listening_times",
+            ORPHANED_LISTENING_TIMES,
+        ),
+        ("Songs without artists", "songs", SONGS_WITHOUT_ARTISTS),
+        ("Songs with empty titles", "songs", SONGS_WITHOUT_TITLES),
+    ];
+    let mut problems = 0;
+    for (name, table, condition) in checks {
+        let count = sqlx::query_scalar::<_, i64>(&format!(
+            "SELECT COUNT(*) FROM {table} WHERE {condition}"
+        ))
+        .fetch_one(pool)
+        .await?;
+        println!("{name}: {count}");
+        problems += count;
+    }
+    // Anything that writes listening times without going through the storage, like an older
+    // version sharing the database or a manual edit, leaves the rollups behind
+    let mut stale_rollups = 0;
+    for rollup in Rollup::ALL {
+        let table = rollup.table();
+        let totals = rollup.totals();
+        stale_rollups += sqlx::query_scalar::<_, i64>(&format!(
+            "SELECT (SELECT COUNT(*) FROM ({totals} EXCEPT SELECT {ROLLUP_COLUMNS} FROM {table})) + (SELECT COUNT(*) FROM (SELECT {ROLLUP_COLUMNS} FROM {table} EXCEPT {totals}))"
+        ))
+        .fetch_one(pool)
+        .await?;
+    }
+    println!("Rollup rows out of sync with listening times: {stale_rollups}");
+    problems += stale_rollups;
+
+    if problems == 0 {
+        println!("No problems found");
+    } else if repair {
+        let mut tx = pool.begin().await?;
+        sqlx::query("INSERT INTO artists (name) VALUES ($1) ON CONFLICT (name) DO NOTHING")
+            .bind(UNKNOWN)
+            .execute(&mut *tx)
+            .await?;
+        // A song can't be moved when the new artist or title is already taken by another
+        // one, those are left for the user to sort out
+        let artists = sqlx::query(&format!(
+            "UPDATE OR IGNORE songs SET artist_id = (SELECT id FROM artists WHERE name = $1) WHERE {SONGS_WITHOUT_ARTISTS}"
+        ))
+        .bind(UNKNOWN)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        let titles = sqlx::query(&format!(
+            "UPDATE OR IGNORE songs SET title = $1 WHERE {SONGS_WITHOUT_TITLES}"
+        ))
+        .bind(UNKNOWN)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        let listening_times = sqlx::query(&format!(
+            "DELETE FROM listening_times WHERE {ORPHANED_LISTENING_TIMES}"
+        ))
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if stale_rollups > 0 {
+            for rollup in Rollup::ALL {
+                let table = rollup.table();
+                sqlx::query(&format!("DELETE FROM {table}"))
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&format!(
+                    "INSERT INTO {table} ({ROLLUP_COLUMNS}) {}",
+                    rollup.totals()
+                ))
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        let left = sqlx::query_scalar::<_, i64>(&format!(
+            "SELECT COUNT(*) FROM songs WHERE {SONGS_WITHOUT_ARTISTS} OR {SONGS_WITHOUT_TITLES}"
+        ))
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        println!(
+            "Moved {artists} songs to the artist '{UNKNOWN}', renamed {titles} songs to '{UNKNOWN}' and removed {listening_times} listening times"
+        );
+        if stale_rollups > 0 {
+            println!("Rebuilt the rollups from the listening times");
+        }
+        if left > 0 {
+            println!(
+                "{left} songs clash with an existing '{UNKNOWN}' song and have to be fixed by hand"
+            );
+        }
+    } else {
+        println!(
+            "Run `{} db check --repair` to give songs an '{UNKNOWN}' artist or title, remove listening times without a song and rebuild the rollups",
+            env!("CARGO_PKG_NAME")
+        );
+    }
+    Ok(())
+}
+
+/// Write a consistent copy of the database into `dir` using `VACUUM INTO`, then delete all but
+/// the `keep` newest backups. Returns the path of the new backup.
+pub async fn backup(pool: &SqlitePool, db_file: &Path, dir: &Path, keep: u32) -> Result<PathBuf> {
+    // Opening the pool would otherwise create an empty database just to back it up
+    if !db_file.is_file() {
+        bail!("There is no database at '{}' to back up", db_file.display());
+    }
+    let stem = backup_stem(db_file);
+    create_dir_all(dir)?;
+    let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+    // VACUUM INTO won't overwrite a file, so backups taken in the same second get a counter
+    let path = (0..)
+        .map(|i| match i {
+            0 => dir.join(format!("{stem}-{timestamp}.db")),
+            i => dir.join(format!("{stem}-{timestamp}-{i}.db")),
+        })
+        .find(|x| !x.exists())
+        .expect("Counting up always finds a free name");
+    sqlx::query("VACUUM INTO $1")
+        .bind(path.to_string_lossy())
+        .execute(pool)
+        .await?;
+
+    let mut backups = backups(dir, &stem)?;
+    backups.sort();
+    for (_, old) in backups
+        .iter()
+        .rev()
+        .filter(|(_, x)| *x != path)
+        .skip(keep.saturating_sub(1) as usize)
+    {
+        info!("Removing old backup '{}'", old.display());
+        remove_file(old)?;
+    }
+    Ok(path)
+}
+
+/// Back up the database whenever the newest backup in `dir` is more than a day old
+pub async fn backup_daily(pool: SqlitePool, db_file: PathBuf, dir: PathBuf, keep: u32) {
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    loop {
+        let newest = backups(&dir, &backup_stem(&db_file))
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|(_, x)| x.metadata().and_then(|x| x.modified()).ok())
+            .max();
+        if newest.is_none_or(|x| x.elapsed().unwrap_or_default() >= DAY) {
+            match backup(&pool, &db_file, &dir, keep).await {
+                Ok(path) => info!("Backed up database to '{}'", path.display()),
+                Err(e) => error!("Error backing up database: '{}'", e),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+/// Backups are named after the database they were taken from so that several databases can
+/// share one backup directory
+fn backup_stem(db_file: &Path) -> String {
+    db_file
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or(env!("CARGO_PKG_NAME").into())
+}
+
+/// Backups of the database named `stem` in `dir`, each with a key that sorts them from oldest
+/// to newest. Only names that are exactly the stem followed by a timestamp count, since the
+/// backups of `mpdtrackr-work.db` also start with `mpdtrackr-`.
+fn backups(dir: &Path, stem: &str) -> Result<Vec<((String, u32), PathBuf)>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let name = Regex::new(&format!(
+        r"^{}-(\d{{8}}-\d{{6}})(?:-(\d+))?\.db$",
+        regex::escape(stem)
+    ))?;
+    Ok(read_dir(dir)?
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter_map(|path| {
+            let captures = name.captures(path.file_name()?.to_str()?)?;
+            let counter = captures
+                .get(2)
+                .map_or(Some(0), |x| x.as_str().parse().ok())?;
+            Some(((captures[1].to_owned(), counter), path))
+        })
+        .collect())
+}
+
+/// Default directory for backups of `db_file`
+pub fn default_backup_dir(db_file: &Path) -> PathBuf {
+    db_file.parent().unwrap_or(Path::new("")).join("backups")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    use super::*;
+    use crate::storage::{SqliteStorage, Storage};
+
+    /// Empty database in memory without any migrations applied
+    async fn empty_database() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Database as left behind by the versions before migrations, which had the device column
+    /// but no unique indexes
+    async fn legacy_database() -> SqlitePool {
+        let pool = empty_database().await;
+        for migration in &MIGRATIONS[..2] {
+            sqlx::query(migration.sql).execute(&pool).await.unwrap();
+        }
+        sqlx::query(
+            "
+INSERT INTO artists (id, name) VALUES (1, 'Muse'), (2, 'muse');
+INSERT INTO songs (id, title, artist_id) VALUES (1, 'Uprising', 1), (2, 'Uprising', 2);
+INSERT INTO listening_times (date, song_id, playback_time, device) VALUES
+ ('2024-01-01', 1, 10, 'desk'),
+ ('2024-01-01', 2, 5, 'desk'),
+ ('2024-01-02', 1, 7, 'desk');",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    /// Database with the first schema, from before foreign keys were enforced, holding a
+    /// listening time of a song that has since been deleted
+    async fn dangling_database() -> SqlitePool {
+        let pool = empty_database().await;
+        sqlx::query(MIGRATIONS[0].sql).execute(&pool).await.unwrap();
+        sqlx::query(
+            "
+PRAGMA foreign_keys = OFF;
+INSERT INTO artists (id, name) VALUES (1, 'Muse');
+INSERT INTO songs (id, title, artist_id) VALUES (1, 'Uprising', 1);
+INSERT INTO listening_times (date, song_id, playback_time) VALUES
+ ('2024-01-01', 1, 10),
+ ('2024-01-01', 99, 5);
+PRAGMA foreign_keys = ON;",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn migrates_new_databases() {
+        let storage = SqliteStorage::in_memory().await.unwrap();
+        assert_eq!(
+            schema_version(storage.pool()).await.unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+        assert!(pending_migrations(storage.pool()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn merges_duplicates_of_legacy_databases() {
+        let pool = legacy_database().await;
+        assert_eq!(schema_version(&pool).await.unwrap(), 2);
+        migrate(&pool).await.unwrap();
+
+        async fn count(pool: &SqlitePool, table: &str) -> i64 {
+            sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+                .fetch_one(pool)
+                .await
+                .unwrap()
+        }
+        assert_eq!(count(&pool, "artists").await, 1);
+        assert_eq!(count(&pool, "songs").await, 1);
+        assert_eq!(
+            sqlx::query_as::<_, (String, i64, i64)>(
+                "SELECT date, song_id, playback_time FROM listening_times ORDER BY date"
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap(),
+            [("2024-01-01".into(), 1, 15), ("2024-01-02".into(), 1, 7)]
+        );
+        assert_eq!(
+            sqlx::query_scalar::<_, i64>("SELECT SUM(playback_time) FROM rollup_months")
+                .fetch_one(&pool)
+                .await
+                .unwrap(),
+            22
+        );
+        // The versions the legacy schema already had are recorded without running them again
+        assert_eq!(
+            sqlx::query_scalar::<_, i64>("SELECT version FROM schema_version ORDER BY version")
+                .fetch_all(&pool)
+                .await
+                .unwrap(),
+            MIGRATIONS.iter().map(|x| x.version).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn migrates_listening_times_of_deleted_songs() {
+        let pool = dangling_database().await;
+        assert_eq!(schema_version(&pool).await.unwrap(), 1);
+        migrate(&pool).await.unwrap();
+        assert!(pending_migrations(&pool).await.unwrap().is_empty());
+        // The listening time is kept for db check to report, but can't be rolled up
+        assert_eq!(
+            sqlx::query_scalar::<_, i64>("SELECT SUM(playback_time) FROM listening_times")
+                .fetch_one(&pool)
+                .await
+                .unwrap(),
+            15
+        );
+        assert_eq!(
+            sqlx::query_as::<_, (i64, i64)>("SELECT song_id, playback_time FROM rollup_days")
+                .fetch_all(&pool)
+                .await
+                .unwrap(),
+            [(1, 10)]
+        );
+    }
+
+    #[tokio::test]
+    async fn checking_leaves_the_schema_alone() {
+        let pool = legacy_database().await;
+        check(&pool, true).await.unwrap();
+        assert_eq!(schema_version(&pool).await.unwrap(), 2);
+        assert!(!table_exists(&pool, "schema_version").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn repairs_listening_times_before_migrating() {
+        let pool = dangling_database().await;
+        check(&pool, false).await.unwrap();
+        async fn count(pool: &SqlitePool) -> i64 {
+            sqlx::query_scalar("SELECT COUNT(*) FROM listening_times")
+                .fetch_one(pool)
+                .await
+                .unwrap()
+        }
+        assert_eq!(count(&pool).await, 2);
+        check(&pool, true).await.unwrap();
+        assert_eq!(count(&pool).await, 1);
+        assert_eq!(schema_version(&pool).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn repair_keeps_listening_history() {
+        let storage = SqliteStorage::in_memory().await.unwrap();
+        let pool = storage.pool();
+        let artist_id = storage.upsert_artist("Muse").await.unwrap().id;
+        // Older databases didn't enforce their foreign keys
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO songs (id, title, artist_id) VALUES (10, 'Lost', 99), (11, ' ', $1)",
+        )
+        .bind(artist_id)
+        .execute(pool)
+        .await
+        .unwrap();
+        let date: chrono::NaiveDate = "2024-01-01".parse().unwrap();
+        for song_id in [10, 11] {
+            let play = storage
+                .start_play(date.and_hms_opt(12, 0, 0).unwrap(), song_id, "desk")
+                .await
+                .unwrap();
+            storage.add_playback_time(&play, 30).await.unwrap();
+        }
+        // Written behind the storage's back, so the rollups don't know about it
+        sqlx::query(
+            "INSERT INTO listening_times (date, song_id, device, playback_time) VALUES ('2024-01-02', 10, 'desk', 5)",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        check(pool, true).await.unwrap();
+        assert_eq!(
+            sqlx::query_as::<_, (i64, String, String)>(
+                "SELECT songs.id, songs.title, artists.name FROM songs INNER JOIN artists ON artists.id = songs.artist_id ORDER BY songs.id"
+            )
+            .fetch_all(pool)
+            .await
+            .unwrap(),
+            [
+                (10, "Lost".into(), UNKNOWN.into()),
+                (11, UNKNOWN.into(), "Muse".into())
+            ]
+        );
+        for table in [
+            "listening_times",
+            "rollup_days",
+            "rollup_months",
+            "rollup_years",
+        ] {
+            assert_eq!(
+                sqlx::query_scalar::<_, i64>(&format!("SELECT SUM(playback_time) FROM {table}"))
+                    .fetch_one(pool)
+                    .await
+                    .unwrap(),
+                65,
+                "{table}"
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Calendar of daily listening for the `heatmap` subcommand
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use chrono::{Datelike, Days, NaiveDate, Weekday};
+
+use crate::{chart::Charset, structs::DurationFormat};
+
+const SHADES: [char; 5] = ['·', '░', '▒', '▓', '█'];
+const ASCII_SHADES: [char; 5] = ['.', '-', '+', '*', '#'];
+/// Room for the weekday labels in front of the calendar
+const LABEL_WIDTH: usize = 4;
+
+/// Draw a calendar from `start` to `end` with a column per week and a row per weekday,
+/// shading every day by its listening time in `days`
+pub fn write_heatmap(
+    out: &mut impl Write,
+    days: &[(NaiveDate, u64)],
+    (start, end): (NaiveDate, NaiveDate),
+    week_start: Weekday,
+    charset: Charset,
+) -> io::Result<()> {
+    let shades = match charset {
+        Charset::Unicode => &SHADES,
+        Charset::Ascii => &ASCII_SHADES,
+    };
+    let times: HashMap<NaiveDate, u64> = days
+        .iter()
+        .copied()
+        .filter(|(date, _)| (start..=end).contains(date))
+        .collect();
+    let max = times.values().copied().max().unwrap_or_default().max(1);
+    let days_into_week =
+        (7 + start.weekday().num_days_from_monday() - week_start.num_days_from_monday()) % 7;
+    let out_of_range = || io::Error::other(format!("Dates from {start} to {end} are out of range"));
+    let first_week = start
+        .checked_sub_days(Days::new(days_into_week.into()))
+        .ok_or_else(out_of_range)?;
+    let weeks = (end - first_week).num_days() as usize / 7 + 1;
+    let day = |week: usize, weekday: usize| {
+        first_week
+            .checked_add_days(Days::new((week * 7 + weekday) as u64))
+            .ok_or_else(out_of_range)
+    };
+
+    // Months are named above the week they start in, unless the name before is in the way
+    let mut months = String::new();
+    for week in 0..weeks {
+        let first = day(week, 0)?.max(start);
+        let last = day(week, 6)?.min(end);
+        let named = match () {
+            // Leave out the first month if it's only in a few weeks so the next one can be named
+            _ if week == 0 => Some(first).filter(|x| {
+                x.checked_add_days(Days::new(21))
+                    .is_some_and(|y| y.month() == x.month())
+            }),
+            _ if first.day() == 1 || first.month() != last.month() => Some(last),
+            _ => None,
+        };
+        if let Some(date) = named.filter(|_| months.len() <= week) {
+            months.push_str(&" ".repeat(week - months.len()));
+            months.push_str(&date.format("%b").to_string());
+        }
+    }
+    writeln!(out, "{}{months}", " ".repeat(LABEL_WIDTH))?;
+
+    for weekday in 0..7 {
+        let mut line = format!(
+            "{:<LABEL_WIDTH$}",
+            day(0, weekday)?.format("%a").to_string()
+        );
+        for week in 0..weeks {
+            let date = day(week, weekday)?;
+            line.push(match times.get(&date) {
+                _ if !(start..=end).contains(&date) => ' ',
+                Some(&time) if time > 0 => {
+                    let level = (time * 4).div_ceil(max) as usize;
+                    shades[level.clamp(1, 4)]
+                }
+                _ => shades[0],
+            });
+        }
+        writeln!(out, "{}", line.trim_end())?;
+    }
+
+    writeln!(
+        out,
+        "\n{}Less {} More",
+        " ".repeat(LABEL_WIDTH),
+        shades.iter().collect::<String>()
+    )?;
+    let total: u64 = times.values().sum();
+    let busiest = times.iter().max_by_key(|(date, time)| (**time, *date));
+    if let Some((date, time)) = busiest {
+        writeln!(
+            out,
+            "{}{} on {} days, most on {} ({})",
+            " ".repeat(LABEL_WIDTH),
+            DurationFormat::Human.format(total),
+            times.len(),
+            date.format("%b %-d, %Y"),
+            DurationFormat::Human.format(*time)
+        )?;
+    }
+    Ok(())
+}
@@ -1,2 +1,9 @@
+pub mod chart;
+pub mod compare;
+pub mod db;
+pub mod heatmap;
+pub mod storage;
 pub mod structs;
+pub mod table;
+pub mod template;
 pub mod utils;
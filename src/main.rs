@@ -1,85 +1,116 @@
-use std::{
-    env,
-    fs::{create_dir_all, File},
-    time::Duration,
-};
+use std::{env, fs::create_dir_all, time::Duration};
 
+#[cfg(feature = "postgres")]
+use anyhow::anyhow;
 use anyhow::Result;
 use clap::Parser;
 use log::error;
+#[cfg(feature = "postgres")]
+use mpdtrackr::storage::PostgresStorage;
 use mpdtrackr::{
-    structs::{Args, Config, SubCommand},
+    db,
+    storage::{SqliteStorage, Storage},
+    structs::{Args, Config, DbCommand, SubCommand},
     utils::*,
 };
 
-const PKG_NAME: &str = env!("CARGO_PKG_NAME");
-
 #[tokio::main]
 async fn main() -> Result<()> {
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "info")
     }
     pretty_env_logger::init();
-    let db_file = dirs::data_dir()
-        .unwrap()
-        .join(PKG_NAME)
-        .join(concat!(env!("CARGO_PKG_NAME"), ".db"));
-    if !db_file.is_file() {
-        create_dir_all(db_file.parent().expect("DB file should have parent dir"))?;
-        File::create(&db_file)?;
-    }
 
     let args = Args::parse();
-    let pool = sqlx::sqlite::SqlitePool::connect_lazy(&format!("sqlite://{}", db_file.display()))?;
-    let config: Config = Config::from_config_file().unwrap_or_else(|e| {
-        error!(
-            "Error parsing config file: '{}'. Falling back to default config",
-            e
-        );
-        Config::new()
-    });
+    let config: Config = args
+        .config_path()
+        .and_then(|x| Config::from_config_file(&x))
+        .unwrap_or_else(|e| {
+            error!(
+                "Error parsing config file: '{}'. Falling back to default config",
+                e
+            );
+            Config::new()
+        });
+
+    #[cfg(feature = "postgres")]
+    if let Some(url) = args
+        .db
+        .as_deref()
+        .filter(|x| x.starts_with("postgres://") || x.starts_with("postgresql://"))
+    {
+        let storage = PostgresStorage::connect(url).await?;
+        return match args.subcommand {
+            SubCommand::Db(_) => Err(anyhow!(
+                "The db subcommands only work with SQLite databases"
+            )),
+            command => run_command(&storage, command, &config).await,
+        };
+    }
 
-    // create sqlite tables
-    sqlx::query(
-        r#"
-CREATE TABLE IF NOT EXISTS artists (
- id INTEGER PRIMARY KEY AUTOINCREMENT,
- name TEXT NOT NULL COLLATE NOCASE
-);
-CREATE TABLE IF NOT EXISTS songs (
- id INTEGER PRIMARY KEY AUTOINCREMENT,
- title TEXT NOT NULL COLLATE NOCASE,
- artist_id INTEGER,
- album TEXT COLLATE NOCASE,
- genre TEXT COLLATE NOCASE,
- duration INTEGER,
- FOREIGN KEY (artist_id)
-    REFERENCES artists (id) 
-    ON UPDATE CASCADE
-    ON DELETE CASCADE
-);
-CREATE TABLE IF NOT EXISTS listening_times (
- id INTEGER PRIMARY KEY AUTOINCREMENT,
- date DATE,
- song_id INTEGER,
- playback_time INTEGER NOT NULL,
- FOREIGN KEY (song_id)
-    REFERENCES songs (id) 
-    ON UPDATE SET NULL
-    ON DELETE SET NULL
-);"#,
-    )
-    .execute(&pool)
-    .await?;
+    let db_file = args.db_path()?;
+    if let Some(parent) = db_file.parent() {
+        create_dir_all(parent)?;
+    }
+    let storage = SqliteStorage::open(&db_file);
+    let pool = storage.pool();
 
     match args.subcommand {
+        // The db subcommands decide for themselves whether the schema should be touched
+        SubCommand::Db(args) => match args.subcommand {
+            DbCommand::Migrate { dry_run } => db::run_migrations(pool, dry_run).await,
+            DbCommand::Check { repair } => db::check(pool, repair).await,
+            DbCommand::Backup { path, keep } => {
+                let dir = path
+                    .or(config.backup_dir.clone())
+                    .unwrap_or_else(|| db::default_backup_dir(&db_file));
+                let path =
+                    db::backup(pool, &db_file, &dir, keep.unwrap_or(config.backup_keep)).await?;
+                println!("Backed up database to '{}'", path.display());
+                Ok(())
+            }
+        },
+        command => {
+            db::migrate(pool).await?;
+            if matches!(command, SubCommand::Run) && config.daily_backup {
+                tokio::spawn(db::backup_daily(
+                    pool.clone(),
+                    db_file.clone(),
+                    config
+                        .backup_dir
+                        .clone()
+                        .unwrap_or_else(|| db::default_backup_dir(&db_file)),
+                    config.backup_keep,
+                ));
+            }
+            run_command(&storage, command, &config).await
+        }
+    }
+}
+
+async fn run_command<S: Storage>(storage: &S, command: SubCommand, config: &Config) -> Result<()> {
+    match command {
         SubCommand::Run => loop {
-            error!("{:?}", run(&pool, &config).await);
+            error!("{:?}", run(storage, config).await);
             std::thread::sleep(Duration::from_secs(1));
         },
-        SubCommand::Print(args) => print(&pool, args).await?,
+        SubCommand::Print(mut args) => {
+            args.resolve_dates(chrono::Local::now().date_naive(), config.week_start)?;
+            args.resolve_template(&config.templates)?;
+            print(storage, *args).await
+        }
+        SubCommand::Heatmap(mut args) => {
+            args.filters
+                .resolve_dates(chrono::Local::now().date_naive(), config.week_start)?;
+            heatmap(storage, *args, config.week_start).await
+        }
+        SubCommand::Compare(mut args) => {
+            args.filters
+                .resolve_dates(chrono::Local::now().date_naive(), config.week_start)?;
+            compare(storage, *args, config.week_start).await
+        }
+        SubCommand::Db(_) => unreachable!("db subcommands only work on SQLite databases"),
         // SubCommand::Export { files } => export(files).await,
         // SubCommand::Import { files } => import(files).await,
     }
-    Ok(())
 }
@@ -0,0 +1,104 @@
+//! Places the listening stats can be kept in. The daemon and `print` only talk to the
+//! [`Storage`] trait, so the library can be embedded on top of any database that implements it.
+
+use std::future::Future;
+
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveDateTime};
+
+use crate::structs::{DataRow, Filters, PrintArgs, Summary};
+
+#[cfg(feature = "postgres")]
+mod postgres;
+pub(crate) mod sql;
+mod sqlite;
+
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// Song metadata as reported by MPD
+#[derive(Debug, Clone)]
+pub struct NewSong<'a> {
+    pub title: &'a str,
+    pub artist_id: i64,
+    pub album: Option<&'a str>,
+    pub genre: Option<&'a str>,
+    pub duration: Option<u32>,
+}
+
+/// Result of inserting a row that might already exist
+#[derive(Debug, Clone, Copy)]
+pub struct Upserted {
+    pub id: i64,
+    /// Whether the row didn't exist before
+    pub inserted: bool,
+}
+
+/// A play of a song recorded with [`Storage::start_play`]
+#[derive(Debug, Clone)]
+pub struct Play {
+    pub id: i64,
+    pub song_id: i64,
+    /// Day the play started on
+    pub date: NaiveDate,
+    pub device: Box<str>,
+}
+
+pub trait Storage: Sync {
+    /// Insert the artist if it isn't known yet and return its id
+    fn upsert_artist(&self, name: &str) -> impl Future<Output = Result<Upserted>> + Send;
+
+    /// Insert the song if it isn't known yet and return its id
+    fn upsert_song(&self, song: &NewSong<'_>) -> impl Future<Output = Result<Upserted>> + Send;
+
+    /// Record that the song started playing on `device` at the local time `started_at`
+    fn start_play(
+        &self,
+        started_at: NaiveDateTime,
+        song_id: i64,
+        device: &str,
+    ) -> impl Future<Output = Result<Play>> + Send;
+
+    /// Add `seconds` to the time the play lasted, which counts towards the day it started on
+    fn add_playback_time(
+        &self,
+        play: &Play,
+        seconds: u32,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Count the play as skipped
+    fn skip_play(&self, play: &Play) -> impl Future<Output = Result<()>> + Send;
+
+    /// Aggregate the listening times according to the grouping, filtering and sorting options,
+    /// handing every row to `on_row` as soon as it is read from the database. Rows grouped by
+    /// time have the key of their time bucket as date, see [`crate::structs::TimeGroup::label`].
+    fn for_each_stat(
+        &self,
+        command: &PrintArgs,
+        on_row: impl FnMut(DataRow) -> Result<()> + Send,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Totals over everything the `filters` match
+    fn summary(&self, filters: &Filters) -> impl Future<Output = Result<Summary>> + Send;
+
+    /// Listening time in seconds of every day with any listening that the `filters` match,
+    /// ordered by date
+    fn daily_totals(
+        &self,
+        filters: &Filters,
+    ) -> impl Future<Output = Result<Vec<(NaiveDate, u64)>>> + Send;
+
+    /// Same as [`Storage::for_each_stat`] but collects the rows
+    fn stats(&self, command: &PrintArgs) -> impl Future<Output = Result<Vec<DataRow>>> + Send {
+        async move {
+            let mut rows = Vec::new();
+            self.for_each_stat(command, |row| {
+                rows.push(row);
+                Ok(())
+            })
+            .await?;
+            Ok(rows)
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveDateTime};
+use futures::TryStreamExt;
+use log::info;
+use sqlx::{postgres::PgRow, PgConnection, PgPool, Postgres, Row};
+
+use super::{
+    sql::{daily_query, decode_day, decode_summary, stats_query, summary_query, Dialect},
+    NewSong, Play, Storage, Upserted,
+};
+use crate::{
+    db::Migration,
+    structs::{DataRow, Filters, PrintArgs, Summary},
+};
+
+/// The Postgres schema starts out where the SQLite one is now, so it has its own history
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create artists, songs and listening_times tables",
+        sql: include_str!("../../migrations/postgres/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "count plays and skips",
+        sql: include_str!("../../migrations/postgres/0002_plays.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "log every play with the time it started",
+        sql: include_str!("../../migrations/postgres/0003_play_log.sql"),
+    },
+];
+
+/// Storage on a Postgres server, meant for sharing stats between several users or machines.
+/// Unlike with SQLite, names are compared case sensitively.
+#[derive(Debug, Clone)]
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    /// Connect to the database at `url` and bring its schema up to date
+    pub async fn connect(url: &str) -> Result<Self> {
+        let storage = Self::new(PgPool::connect(url).await?);
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+CREATE TABLE IF NOT EXISTS schema_version (
+ version BIGINT PRIMARY KEY,
+ description TEXT NOT NULL,
+ applied_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        let version =
+            sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(version) FROM schema_version")
+                .fetch_one(&self.pool)
+                .await?
+                .unwrap_or_default();
+        for migration in MIGRATIONS.iter().filter(|x| x.version > version) {
+            info!(
+                "Applying database migration {:04}: {}",
+                migration.version, migration.description
+            );
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(migration.sql).execute(&mut *tx).await?;
+            sqlx::query("INSERT INTO schema_version (version, description) VALUES ($1, $2)")
+                .bind(migration.version)
+                .bind(migration.description)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for PostgresStorage {
+    async fn upsert_artist(&self, name: &str) -> Result<Upserted> {
+        let inserted =
+            sqlx::query("INSERT INTO artists (name) VALUES ($1) ON CONFLICT (name) DO NOTHING")
+                .bind(name)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0;
+        let id = sqlx::query_scalar("SELECT id FROM artists WHERE name = $1")
+            .bind(name)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(Upserted { id, inserted })
+    }
+
+    async fn upsert_song(&self, song: &NewSong<'_>) -> Result<Upserted> {
+        let inserted = sqlx::query(
+            "INSERT INTO songs (title, artist_id, album, genre, duration) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (title, artist_id) DO NOTHING",
+        )
+        .bind(song.title)
+        .bind(song.artist_id)
+        .bind(song.album)
+        .bind(song.genre)
+        .bind(song.duration.map(i64::from))
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        let id = sqlx::query_scalar("SELECT id FROM songs WHERE title = $1 AND artist_id = $2")
+            .bind(song.title)
+            .bind(song.artist_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(Upserted { id, inserted })
+    }
+
+    async fn start_play(
+        &self,
+        started_at: NaiveDateTime,
+        song_id: i64,
+        device: &str,
+    ) -> Result<Play> {
+        let date = started_at.date();
+        let mut tx = self.pool.begin().await?;
+        let id = sqlx::query_scalar(
+            "INSERT INTO plays (date, started_at, song_id, device) VALUES ($1, $2, $3, $4) RETURNING id",
+        )
+        .bind(date)
+        .bind(started_at)
+        .bind(song_id)
+        .bind(device)
+        .fetch_one(&mut *tx)
+        .await?;
+        add_to_totals(&mut tx, date, song_id, device, 0, 1, 0).await?;
+        tx.commit().await?;
+        Ok(Play {
+            id,
+            song_id,
+            date,
+            device: device.into(),
+        })
+    }
+
+    async fn add_playback_time(&self, play: &Play, seconds: u32) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE plays SET playback_time = playback_time + $1 WHERE id = $2")
+            .bind(i64::from(seconds))
+            .bind(play.id)
+            .execute(&mut *tx)
+            .await?;
+        add_to_totals(
+            &mut tx,
+            play.date,
+            play.song_id,
+            &play.device,
+            seconds,
+            0,
+            0,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn skip_play(&self, play: &Play) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE plays SET skips = 1 WHERE id = $1")
+            .bind(play.id)
+            .execute(&mut *tx)
+            .await?;
+        add_to_totals(&mut tx, play.date, play.song_id, &play.device, 0, 0, 1).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn summary(&self, filters: &Filters) -> Result<Summary> {
+        let row = summary_query::<Postgres>(filters, Dialect::Postgres)
+            .build()
+            .fetch_one(&self.pool)
+            .await?;
+        decode_summary(&row)
+    }
+
+    async fn daily_totals(&self, filters: &Filters) -> Result<Vec<(NaiveDate, u64)>> {
+        daily_query::<Postgres>(filters, Dialect::Postgres)
+            .build()
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(decode_day)
+            .collect()
+    }
+
+    async fn for_each_stat(
+        &self,
+        command: &PrintArgs,
+        mut on_row: impl FnMut(DataRow) -> Result<()> + Send,
+    ) -> Result<()> {
+        let mut query = stats_query::<Postgres>(command, Dialect::Postgres);
+        let mut rows = query.build().fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            on_row(data_row(&row)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Add to the totals of the song on `device` during `date`
+async fn add_to_totals(
+    conn: &mut PgConnection,
+    date: NaiveDate,
+    song_id: i64,
+    device: &str,
+    seconds: u32,
+    plays: u32,
+    skips: u32,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO listening_times (date, song_id, device, playback_time, plays, skips) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (date, song_id, device) DO UPDATE SET playback_time = listening_times.playback_time + excluded.playback_time, plays = listening_times.plays + excluded.plays, skips = listening_times.skips + excluded.skips",
+    )
+    .bind(date)
+    .bind(song_id)
+    .bind(device)
+    .bind(i64::from(seconds))
+    .bind(i64::from(plays))
+    .bind(i64::from(skips))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Postgres has no unsigned integers, so [`DataRow`] can't be decoded directly
+fn data_row(row: &PgRow) -> Result<DataRow> {
+    let unsigned = |column: &str| -> Result<Option<u32>> {
+        Ok(row
+            .try_get::<Option<i64>, _>(column)?
+            .map(u32::try_from)
+            .transpose()?)
+    };
+    Ok(DataRow {
+        artist_id: unsigned("artist_id")?,
+        song_id: unsigned("song_id")?,
+        title: row.try_get("title")?,
+        artist: row.try_get("artist")?,
+        album: row.try_get("album")?,
+        genre: row.try_get("genre")?,
+        time: unsigned("time")?.unwrap_or_default(),
+        duration: row
+            .try_get::<Option<i32>, _>("duration")?
+            .map(u32::try_from)
+            .transpose()?,
+        first_listened: row.try_get("first_listened")?,
+        last_listened: row.try_get("last_listened")?,
+        times_listened: unsigned("times_listened")?,
+        device: row.try_get("device")?,
+        date: row.try_get("date")?,
+        play_count: unsigned("play_count")?.unwrap_or_default(),
+        skip_rate: row.try_get("skip_rate")?,
+        share: row.try_get("share")?,
+        cumulative_share: row.try_get("cumulative_share")?,
+        rank: unsigned("rank")?.unwrap_or_default(),
+    })
+}
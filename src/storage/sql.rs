@@ -0,0 +1,623 @@
+use anyhow::Result;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use sqlx::{ColumnIndex, Database, Decode, Encode, QueryBuilder, Row, Type};
+
+use crate::structs::{Filters, PrintArgs, Summary, TimeGroup, WeekStyle};
+
+/// SQL flavours understood by the stats query builder
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Dialect {
+    Sqlite,
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    Postgres,
+}
+
+impl Dialect {
+    /// Expression for the key of the time bucket rows of `table` belong to, as text so that
+    /// every backend returns the same thing. Along the timeline that is the first day of the
+    /// bucket in Y-M-D format, for cyclic groups the position in the cycle starting from 0
+    /// (or 1 for months).
+    fn time_bucket(&self, group: &TimeGroup, weeks: WeekStyle, table: &str) -> String {
+        let date = format!("{table}.date");
+        // Days to go back from the day of the week (0 being Sunday) to reach the first day of
+        // its week
+        let week_offset = 7 - weeks.first_day().num_days_from_sunday();
+        match self {
+            Dialect::Sqlite => match group {
+                TimeGroup::Day => format!("date({date})"),
+                TimeGroup::Week => format!(
+                    "date({date}, '-' || ((CAST(strftime('%w', {date}) AS INTEGER) + {week_offset}) % 7) || ' days')"
+                ),
+                TimeGroup::Month => format!("date({date}, 'start of month')"),
+                TimeGroup::Quarter => format!(
+                    "date({date}, 'start of month', '-' || ((CAST(strftime('%m', {date}) AS INTEGER) - 1) % 3) || ' months')"
+                ),
+                TimeGroup::Year => format!("date({date}, 'start of year')"),
+                TimeGroup::Decade => format!(
+                    "printf('%04d-01-01', CAST(strftime('%Y', {date}) AS INTEGER) / 10 * 10)"
+                ),
+                TimeGroup::HourOfDay => format!("strftime('%H', {table}.started_at)"),
+                TimeGroup::DayOfWeek => format!(
+                    "CAST((CAST(strftime('%w', {date}) AS INTEGER) + {week_offset}) % 7 AS TEXT)"
+                ),
+                TimeGroup::MonthOfYear => format!("strftime('%m', {date})"),
+                TimeGroup::AllTime => unreachable!(),
+            },
+            Dialect::Postgres => match group {
+                TimeGroup::Day => format!("to_char({date}, 'YYYY-MM-DD')"),
+                TimeGroup::Week => format!(
+                    "to_char({date} - ((EXTRACT(DOW FROM {date})::integer + {week_offset}) % 7), 'YYYY-MM-DD')"
+                ),
+                TimeGroup::Month => format!("to_char(date_trunc('month', {date}), 'YYYY-MM-DD')"),
+                TimeGroup::Quarter => {
+                    format!("to_char(date_trunc('quarter', {date}), 'YYYY-MM-DD')")
+                }
+                TimeGroup::Year => format!("to_char(date_trunc('year', {date}), 'YYYY-MM-DD')"),
+                TimeGroup::Decade => format!("to_char(date_trunc('decade', {date}), 'YYYY-MM-DD')"),
+                TimeGroup::HourOfDay => format!("to_char({table}.started_at, 'HH24')"),
+                TimeGroup::DayOfWeek => {
+                    format!("((EXTRACT(DOW FROM {date})::integer + {week_offset}) % 7)::text")
+                }
+                TimeGroup::MonthOfYear => format!("to_char({date}, 'MM')"),
+                TimeGroup::AllTime => unreachable!(),
+            },
+        }
+    }
+
+    /// Start of a condition that is true when `column` matches the regex bound after it
+    fn regex_match(&self, column: &str) -> String {
+        match self {
+            Dialect::Sqlite => format!("{column} REGEXP "),
+            Dialect::Postgres => format!("{column} ~ "),
+        }
+    }
+}
+
+/// Tables that hold the listening times summed up per period, maintained alongside
+/// `listening_times` by [`super::SqliteStorage`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Rollup {
+    Days,
+    Months,
+    Years,
+}
+
+impl Rollup {
+    /// From the coarsest to the most detailed, which is the order the planner prefers them in
+    pub(crate) const ALL: [Rollup; 3] = [Rollup::Years, Rollup::Months, Rollup::Days];
+
+    pub(crate) fn table(&self) -> &'static str {
+        match self {
+            Rollup::Days => "rollup_days",
+            Rollup::Months => "rollup_months",
+            Rollup::Years => "rollup_years",
+        }
+    }
+
+    /// SQLite expression for the first day of the period `date` falls into
+    pub(crate) fn period_start(&self, date: &str) -> String {
+        match self {
+            Rollup::Days => format!("date({date})"),
+            Rollup::Months => format!("date({date}, 'start of month')"),
+            Rollup::Years => format!("date({date}, 'start of year')"),
+        }
+    }
+
+    /// Query summing `listening_times` up into the rows this rollup should hold, in the order
+    /// of the rollup's columns. Listening times without a song can't be rolled up.
+    pub(crate) fn totals(&self) -> String {
+        format!(
+            "SELECT {} AS date, song_id, device, SUM(playback_time) AS playback_time, SUM(plays) AS plays, SUM(skips) AS skips, MIN(date) AS first_listened, MAX(date) AS last_listened FROM listening_times WHERE date IS NOT NULL AND song_id IN (SELECT id FROM songs) GROUP BY 1, song_id, device",
+            self.period_start("date")
+        )
+    }
+
+    fn is_period_start(&self, date: NaiveDate) -> bool {
+        match self {
+            Rollup::Days => true,
+            Rollup::Months => date.day() == 1,
+            Rollup::Years => date.ordinal() == 1,
+        }
+    }
+
+    /// Whether the rows of this rollup can answer the query without losing precision, which
+    /// requires its periods to be no longer than the time buckets and to never straddle a
+    /// date bound
+    fn answers(&self, command: &PrintArgs) -> bool {
+        let fits_time_group = match command
+            .time_group
+            .as_ref()
+            .expect("Default value set by clap and PrintArgs::new")
+        {
+            TimeGroup::AllTime | TimeGroup::Year | TimeGroup::Decade => true,
+            TimeGroup::Month | TimeGroup::Quarter | TimeGroup::MonthOfYear => {
+                !matches!(self, Rollup::Years)
+            }
+            TimeGroup::Week | TimeGroup::Day | TimeGroup::DayOfWeek => {
+                matches!(self, Rollup::Days)
+            }
+            TimeGroup::HourOfDay => false,
+        };
+        let (start, end) = command.filters.date_bounds();
+        let fits_bounds = start.is_none_or(|x| self.is_period_start(x))
+            && end.is_none_or(|x| self.is_period_start(x.succ_opt().unwrap_or(NaiveDate::MAX)));
+        fits_time_group && fits_bounds
+    }
+}
+
+/// Build the query behind `print`, the rows it returns map onto [`crate::structs::DataRow`].
+/// Columns that aren't part of the grouping are wrapped in aggregates so that the query is valid
+/// for databases that don't allow bare columns.
+pub(crate) fn stats_query<'args, DB>(
+    command: &'args PrintArgs,
+    dialect: Dialect,
+) -> QueryBuilder<'args, DB>
+where
+    DB: Database,
+    &'args str: Encode<'args, DB> + Type<DB>,
+    String: Encode<'args, DB> + Type<DB>,
+    NaiveDate: Encode<'args, DB> + Type<DB>,
+    NaiveDateTime: Encode<'args, DB> + Type<DB>,
+{
+    // Rollups are only kept in SQLite, other databases aggregate the raw listening times
+    let rollup = match dialect {
+        Dialect::Sqlite => Rollup::ALL.into_iter().find(|x| x.answers(command)),
+        Dialect::Postgres => None,
+    };
+    // Only the play log knows the time of day
+    let play_log =
+        matches!(command.time_group, Some(TimeGroup::HourOfDay)) || command.filters.has_times();
+    let (table, first_listened, last_listened) = match (play_log, rollup) {
+        (true, _) => ("plays", "date", "date"),
+        (false, Some(rollup)) => (rollup.table(), "first_listened", "last_listened"),
+        _ => ("listening_times", "date", "date"),
+    };
+
+    // Convert the Vec of enums into comma separated strings to feed them into the sql query
+    let sort_sequence = command
+        .sort
+        .iter()
+        .map(|x| {
+            let descending = x.descending != command.reverse;
+            format!("{} {}", x.by, if descending { "DESC" } else { "ASC" })
+        })
+        .reduce(|acc, x| acc + "," + &x)
+        .unwrap_or_default();
+    // Ranks don't depend on the order entries are printed in
+    let rank_sequence = command
+        .sort
+        .iter()
+        .map(|x| {
+            let descending = x.by.ranks_descending();
+            format!("{} {}", x.by, if descending { "DESC" } else { "ASC" })
+        })
+        .reduce(|acc, x| acc + "," + &x)
+        .unwrap_or_default();
+    // Break ties by rank so that the order always agrees with it
+    let rank_order = match command.sort.first() {
+        Some(x) if (x.descending != command.reverse) != x.by.ranks_descending() => "rank DESC",
+        _ => "rank",
+    };
+    let time_group = command
+        .time_group
+        .as_ref()
+        .expect("Default value set by clap");
+    let weeks = command.weeks.unwrap_or_default();
+    // The date of all-time rows is the last time they were listened to, so it can't be used to
+    // tell groups apart
+    let partition = match time_group {
+        TimeGroup::AllTime => "",
+        _ => "PARTITION BY date ",
+    };
+    // Shares are taken before --top-per-group and --limit leave entries out, which only ever
+    // cut off the lower ranks so the running total can be taken after them
+    let cumulative = command.cumulative || command.columns.contains(&"cumulative_share");
+    let cumulative_share = match cumulative {
+        true => format!(
+            "SUM(ranked.share) OVER ({partition}ORDER BY ranked.rank ROWS UNBOUNDED PRECEDING)"
+        ),
+        false => "NULL".to_owned(),
+    };
+    let mut builder = QueryBuilder::new(format!(
+        "
+SELECT * FROM (
+SELECT ranked.*, {cumulative_share} AS cumulative_share FROM (
+SELECT
+    stats.*,
+    ROW_NUMBER() OVER ({partition}ORDER BY {rank_sequence}) AS rank,
+    CAST(stats.time AS DOUBLE PRECISION) / NULLIF(SUM(stats.time) OVER ({partition}), 0)
+        AS share
+FROM (
+"
+    ));
+    // Only select the fields that are determined by the grouping, the rest would just be taken
+    // from an arbitrary row of each group
+    builder.push("SELECT\n");
+    for (column, expression) in [
+        ("title", "MIN(songs.title)"),
+        ("album", "MIN(songs.album)"),
+        ("genre", "MIN(songs.genre)"),
+        ("song_id", "MIN(songs.id)"),
+        ("duration", "MIN(songs.duration)"),
+        ("artist", "MIN(artists.name)"),
+        ("artist_id", "MIN(artists.id)"),
+        ("device", "MIN(listening_times.device)"),
+        (
+            "times_listened",
+            "SUM(listening_times.playback_time) / NULLIF(MIN(songs.duration), 0)",
+        ),
+    ] {
+        if command
+            .field_group
+            .iter()
+            .any(|x| x.columns().contains(&column))
+        {
+            builder.push(format!("    {expression} AS {column},\n"));
+        } else {
+            builder.push(format!("    NULL AS {column},\n"));
+        }
+    }
+    // The source table is always aliased as listening_times, its date column holds the start
+    // of each row's period
+    builder.push(format!(
+        "    MIN(listening_times.{first_listened}) AS first_listened,
+    MAX(listening_times.{last_listened}) AS last_listened,
+    SUM(listening_times.playback_time) as time,
+    SUM(listening_times.plays) AS play_count,
+    CAST(SUM(listening_times.skips) AS DOUBLE PRECISION) / NULLIF(SUM(listening_times.plays), 0)
+        AS skip_rate,
+"
+    ));
+    match time_group {
+        TimeGroup::AllTime => builder.push(format!(
+            "CAST(MAX(listening_times.{last_listened}) AS TEXT) as date"
+        )),
+        group => builder.push(format!(
+            "{} AS date",
+            dialect.time_bucket(group, weeks, "listening_times")
+        )),
+    };
+    builder.push(format!(
+        "
+FROM songs
+INNER JOIN {table} AS listening_times
+ON songs.id = listening_times.song_id
+INNER JOIN artists
+ON artists.id = songs.artist_id
+"
+    ));
+
+    push_filters(&mut builder, &command.filters, dialect);
+
+    builder.push(format!(
+        "
+GROUP BY {}",
+        command
+            .field_group
+            .iter()
+            .map(|x| x.to_string())
+            .reduce(|acc, x| acc + ", " + &x)
+            .expect("Default value set by clap")
+    ));
+    if !matches!(time_group, TimeGroup::AllTime) {
+        builder.push(format!(
+            ", {}",
+            dialect.time_bucket(time_group, weeks, "listening_times")
+        ));
+    }
+
+    builder.push("\n) AS stats\n) AS ranked");
+    if let Some(top) = command.top_per_group {
+        builder.push(format!("\nWHERE rank <= {top}"));
+    }
+    if let Some(limit) = command.limit {
+        builder.push(format!("\nORDER BY {rank_sequence}, rank\nLIMIT {limit}"));
+    }
+    builder.push("\n) AS limited");
+    builder.push(format!(
+        "
+ORDER BY {sort_sequence}, {rank_order}"
+    ));
+    builder
+}
+
+/// Push the WHERE clause for the `filters`, the source table has to be aliased as
+/// listening_times
+fn push_filters<'args, DB>(
+    builder: &mut QueryBuilder<'args, DB>,
+    filters: &'args Filters,
+    dialect: Dialect,
+) where
+    DB: Database,
+    &'args str: Encode<'args, DB> + Type<DB>,
+    String: Encode<'args, DB> + Type<DB>,
+    NaiveDate: Encode<'args, DB> + Type<DB>,
+    NaiveDateTime: Encode<'args, DB> + Type<DB>,
+{
+    // Every condition after the first one has to be joined with AND
+    let mut conditions = 0;
+    let mut next_condition = |builder: &mut QueryBuilder<'args, DB>| {
+        builder.push(if conditions == 0 { "WHERE " } else { "\nAND " });
+        conditions += 1;
+    };
+
+    let (start, end) = filters.date_bounds();
+    if let Some(start) = start {
+        next_condition(builder);
+        builder.push("listening_times.date >= ");
+        builder.push_bind(start);
+    }
+    if let Some(end) = end {
+        next_condition(builder);
+        builder.push("listening_times.date <= ");
+        builder.push_bind(end);
+    }
+    let (after, before) = match filters.exclusive {
+        true => (">", "<"),
+        false => (">=", "<="),
+    };
+    let (start, end) = filters.time_bounds();
+    if let Some(start) = start {
+        next_condition(builder);
+        builder.push(format!("listening_times.started_at {after} "));
+        builder.push_bind(start);
+    }
+    if let Some(end) = end {
+        next_condition(builder);
+        builder.push(format!("listening_times.started_at {before} "));
+        builder.push_bind(end);
+    }
+
+    if !filters.device.is_empty() {
+        next_condition(builder);
+        builder.push("listening_times.device IN (");
+        let mut devices = builder.separated(", ");
+        for device in &filters.device {
+            devices.push_bind(device.as_str());
+        }
+        devices.push_unseparated(")");
+    }
+
+    for (column, include, exclude) in [
+        ("artists.name", &filters.artist, &filters.not_artist),
+        ("songs.album", &filters.album, &filters.not_album),
+        ("songs.genre", &filters.genre, &filters.not_genre),
+        ("songs.title", &filters.title, &filters.not_title),
+    ] {
+        if !include.is_empty() {
+            next_condition(builder);
+            push_matches(builder, column, include, dialect);
+        }
+        // Missing values never match a pattern, so they shouldn't be left out either
+        if !exclude.is_empty() {
+            next_condition(builder);
+            builder.push("NOT COALESCE(");
+            push_matches(builder, column, exclude, dialect);
+            builder.push(", FALSE)");
+        }
+    }
+}
+
+/// Query for the [`Summary`] of everything the `filters` match,
+/// returning time, songs, artists, albums and days
+pub(crate) fn summary_query<'args, DB>(
+    filters: &'args Filters,
+    dialect: Dialect,
+) -> QueryBuilder<'args, DB>
+where
+    DB: Database,
+    &'args str: Encode<'args, DB> + Type<DB>,
+    String: Encode<'args, DB> + Type<DB>,
+    NaiveDate: Encode<'args, DB> + Type<DB>,
+    NaiveDateTime: Encode<'args, DB> + Type<DB>,
+{
+    // Albums of different artists can share a name
+    let mut builder = QueryBuilder::new(format!(
+        "
+SELECT
+    CAST(COALESCE(SUM(listening_times.playback_time), 0) AS BIGINT) AS time,
+    COUNT(DISTINCT songs.id) AS songs,
+    COUNT(DISTINCT songs.artist_id) AS artists,
+    COUNT(DISTINCT CAST(songs.artist_id AS TEXT) || '/' || songs.album) AS albums,
+    COUNT(DISTINCT CASE WHEN listening_times.playback_time > 0 THEN listening_times.date END)
+        AS days
+FROM songs
+INNER JOIN {} AS listening_times
+ON songs.id = listening_times.song_id
+INNER JOIN artists
+ON artists.id = songs.artist_id
+",
+        source_table(filters)
+    ));
+    push_filters(&mut builder, filters, dialect);
+    builder
+}
+
+/// Query for the listening time of every day with any listening that the `filters` match,
+/// returning date and time ordered by date
+pub(crate) fn daily_query<'args, DB>(
+    filters: &'args Filters,
+    dialect: Dialect,
+) -> QueryBuilder<'args, DB>
+where
+    DB: Database,
+    &'args str: Encode<'args, DB> + Type<DB>,
+    String: Encode<'args, DB> + Type<DB>,
+    NaiveDate: Encode<'args, DB> + Type<DB>,
+    NaiveDateTime: Encode<'args, DB> + Type<DB>,
+{
+    let mut builder = QueryBuilder::new(format!(
+        "
+SELECT
+    listening_times.date AS date,
+    CAST(SUM(listening_times.playback_time) AS BIGINT) AS time
+FROM songs
+INNER JOIN {} AS listening_times
+ON songs.id = listening_times.song_id
+INNER JOIN artists
+ON artists.id = songs.artist_id
+",
+        source_table(filters)
+    ));
+    push_filters(&mut builder, filters, dialect);
+    // Starting a song adds a row before any time is listened
+    builder.push(
+        "\nGROUP BY listening_times.date\nHAVING SUM(listening_times.playback_time) > 0\nORDER BY listening_times.date",
+    );
+    builder
+}
+
+/// Table the summary and daily totals are read from, aliased as listening_times
+fn source_table(filters: &Filters) -> &'static str {
+    match filters.has_times() {
+        true => "plays",
+        false => "listening_times",
+    }
+}
+
+/// Read the row returned by [`summary_query`]
+pub(crate) fn decode_summary<R>(row: &R) -> Result<Summary>
+where
+    R: Row,
+    for<'r> i64: Decode<'r, R::Database> + Type<R::Database>,
+    for<'a> &'a str: ColumnIndex<R>,
+{
+    let count = |column: &str| -> Result<u32> { Ok(row.try_get::<i64, _>(column)?.try_into()?) };
+    Ok(Summary::new(
+        row.try_get::<i64, _>("time")?.try_into()?,
+        count("songs")?,
+        count("artists")?,
+        count("albums")?,
+        count("days")?,
+    ))
+}
+
+/// Read a row returned by [`daily_query`]
+pub(crate) fn decode_day<R>(row: &R) -> Result<(NaiveDate, u64)>
+where
+    R: Row,
+    for<'r> i64: Decode<'r, R::Database> + Type<R::Database>,
+    for<'r> NaiveDate: Decode<'r, R::Database> + Type<R::Database>,
+    for<'a> &'a str: ColumnIndex<R>,
+{
+    Ok((
+        row.try_get("date")?,
+        row.try_get::<i64, _>("time")?.try_into()?,
+    ))
+}
+
+/// Push a condition that is true when `column` matches any of the `patterns`, see the help of
+/// [`PrintArgs`] for the pattern syntax
+fn push_matches<'args, DB>(
+    builder: &mut QueryBuilder<'args, DB>,
+    column: &str,
+    patterns: &'args [String],
+    dialect: Dialect,
+) where
+    DB: Database,
+    &'args str: Encode<'args, DB> + Type<DB>,
+    String: Encode<'args, DB> + Type<DB>,
+{
+    builder.push("(");
+    for (i, pattern) in patterns.iter().enumerate() {
+        if i > 0 {
+            builder.push(" OR ");
+        }
+        if let Some(regex) = pattern.strip_prefix('/').and_then(|x| x.strip_suffix('/')) {
+            builder.push(dialect.regex_match(column));
+            builder.push_bind(regex);
+        } else if is_glob(pattern) {
+            builder.push(dialect.regex_match(column));
+            builder.push_bind(glob_to_regex(pattern));
+        } else {
+            match dialect {
+                // The columns already compare case insensitively in SQLite
+                Dialect::Sqlite => {
+                    builder.push(format!("{column} = "));
+                    builder.push_bind(pattern.as_str());
+                }
+                Dialect::Postgres => {
+                    builder.push(format!("LOWER({column}) = "));
+                    builder.push_bind(pattern.to_lowercase());
+                }
+            }
+        }
+    }
+    builder.push(")");
+}
+
+/// Whether `pattern` has to be matched as a glob rather than compared as is
+pub(crate) fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Translate a shell style glob into a case insensitive regex that only matches whole values
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("(?i)^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                regex.push('[');
+                let mut class = chars.by_ref().take_while(|&x| x != ']').peekable();
+                if class.next_if(|&x| x == '!').is_some() {
+                    regex.push('^');
+                }
+                for c in class {
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c if c.is_ascii_punctuation() => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            c => regex.push(c),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    fn matches(glob: &str, value: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(value)
+    }
+
+    #[test]
+    fn translates_globs() {
+        assert_eq!(glob_to_regex("Song*"), "(?i)^Song.*$");
+        assert_eq!(glob_to_regex("a?c"), "(?i)^a.c$");
+        assert_eq!(glob_to_regex("[!a-c]x"), "(?i)^[^a-c]x$");
+        assert_eq!(glob_to_regex("Mr. (Live)*"), r"(?i)^Mr\. \(Live\).*$");
+    }
+
+    #[test]
+    fn globs_match_whole_values_ignoring_case() {
+        assert!(matches("the *s", "The Beatles"));
+        assert!(!matches("the *", "Not the Beatles"));
+        assert!(matches("Song [1-3]", "song 2"));
+        assert!(!matches("Song [1-3]", "Song 4"));
+        assert!(matches("Song [!1-3]", "Song 4"));
+        assert!(matches("What?", "What!"));
+        assert!(matches("1.0*", "1.0 remix"));
+        assert!(!matches("1.0*", "100"));
+    }
+
+    #[test]
+    fn only_wildcards_make_globs() {
+        assert!(is_glob("Song*"));
+        assert!(is_glob("[ab]"));
+        assert!(!is_glob("Mr. Brightside"));
+    }
+}
@@ -0,0 +1,542 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveDateTime};
+use futures::TryStreamExt;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqliteConnection, SqlitePool,
+};
+
+use super::{
+    sql::{daily_query, decode_day, decode_summary, stats_query, summary_query, Dialect, Rollup},
+    NewSong, Play, Storage, Upserted,
+};
+use crate::structs::{DataRow, Filters, PrintArgs, Summary};
+
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Lazily open the database file at `path`, creating it if it doesn't exist
+    pub fn open(path: &Path) -> Self {
+        // SQLite only enforces the foreign keys declared in the schema when asked to, and the
+        // setting has to be applied to every connection in the pool
+        Self::new(
+            SqlitePoolOptions::new().connect_lazy_with(
+                SqliteConnectOptions::new()
+                    .filename(path)
+                    .create_if_missing(true)
+                    .foreign_keys(true)
+                    .with_regexp(),
+            ),
+        )
+    }
+
+    /// Open a database that only lives in memory and has the schema applied, useful for tests
+    /// and for embedding without touching the file system
+    pub async fn in_memory() -> Result<Self> {
+        // Every connection to `:memory:` gets its own database, so the pool must never open a
+        // second one or drop the only one it has
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(
+                SqliteConnectOptions::from_str("sqlite::memory:")?
+                    .foreign_keys(true)
+                    .with_regexp(),
+            )
+            .await?;
+        crate::db::migrate(&pool).await?;
+        Ok(Self::new(pool))
+    }
+
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+impl Storage for SqliteStorage {
+    async fn upsert_artist(&self, name: &str) -> Result<Upserted> {
+        // Upsert instead of checking first so that concurrent writers can't create duplicates
+        let inserted = sqlx::query!(
+            "INSERT INTO artists (name) VALUES ($1) ON CONFLICT (name) DO NOTHING",
+            name
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        let id = sqlx::query!(r#"SELECT id AS "id!" FROM artists WHERE name = $1"#, name)
+            .fetch_one(&self.pool)
+            .await?
+            .id;
+        Ok(Upserted { id, inserted })
+    }
+
+    async fn upsert_song(&self, song: &NewSong<'_>) -> Result<Upserted> {
+        let inserted = sqlx::query!(
+            "INSERT INTO songs (title, artist_id, album, genre, duration) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (title, artist_id) DO NOTHING",
+            song.title,
+            song.artist_id,
+            song.album,
+            song.genre,
+            song.duration
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        let id = sqlx::query!(
+            r#"SELECT id AS "id!" FROM songs WHERE title = $1 AND artist_id = $2"#,
+            song.title,
+            song.artist_id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .id;
+        Ok(Upserted { id, inserted })
+    }
+
+    async fn start_play(
+        &self,
+        started_at: NaiveDateTime,
+        song_id: i64,
+        device: &str,
+    ) -> Result<Play> {
+        let date = started_at.date();
+        let mut tx = self.pool.begin().await?;
+        let id = sqlx::query!(
+            "INSERT INTO plays (date, started_at, song_id, device) VALUES ($1, $2, $3, $4)",
+            date,
+            started_at,
+            song_id,
+            device
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        add_to_totals(&mut tx, date, song_id, device, 0, 1, 0).await?;
+        tx.commit().await?;
+        Ok(Play {
+            id,
+            song_id,
+            date,
+            device: device.into(),
+        })
+    }
+
+    async fn add_playback_time(&self, play: &Play, seconds: u32) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "UPDATE plays SET playback_time = playback_time + $1 WHERE id = $2",
+            seconds,
+            play.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        add_to_totals(
+            &mut tx,
+            play.date,
+            play.song_id,
+            &play.device,
+            seconds,
+            0,
+            0,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn skip_play(&self, play: &Play) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("UPDATE plays SET skips = 1 WHERE id = $1", play.id)
+            .execute(&mut *tx)
+            .await?;
+        add_to_totals(&mut tx, play.date, play.song_id, &play.device, 0, 0, 1).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn summary(&self, filters: &Filters) -> Result<Summary> {
+        let row = summary_query(filters, Dialect::Sqlite)
+            .build()
+            .fetch_one(&self.pool)
+            .await?;
+        decode_summary(&row)
+    }
+
+    async fn daily_totals(&self, filters: &Filters) -> Result<Vec<(NaiveDate, u64)>> {
+        daily_query(filters, Dialect::Sqlite)
+            .build()
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(decode_day)
+            .collect()
+    }
+
+    async fn for_each_stat(
+        &self,
+        command: &PrintArgs,
+        mut on_row: impl FnMut(DataRow) -> Result<()> + Send,
+    ) -> Result<()> {
+        let mut query = stats_query(command, Dialect::Sqlite);
+        let mut rows = query.build_query_as::<DataRow>().fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            on_row(row)?;
+        }
+        Ok(())
+    }
+}
+
+/// Add to the totals of the song on `device` during `date`, keeping the rollups in the same
+/// transaction so they can never disagree with the listening times
+async fn add_to_totals(
+    conn: &mut SqliteConnection,
+    date: NaiveDate,
+    song_id: i64,
+    device: &str,
+    seconds: u32,
+    plays: u32,
+    skips: u32,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO listening_times (date, song_id, device, playback_time, plays, skips) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (date, song_id, device) DO UPDATE SET playback_time = playback_time + excluded.playback_time, plays = plays + excluded.plays, skips = skips + excluded.skips",
+        date,
+        song_id,
+        device,
+        seconds,
+        plays,
+        skips
+    )
+    .execute(&mut *conn)
+    .await?;
+    for rollup in Rollup::ALL {
+        sqlx::query(&format!(
+            "INSERT INTO {} (date, song_id, device, playback_time, plays, skips, first_listened, last_listened) VALUES ({}, $2, $3, $4, $5, $6, $1, $1) ON CONFLICT (date, song_id, device) DO UPDATE SET playback_time = playback_time + excluded.playback_time, plays = plays + excluded.plays, skips = skips + excluded.skips, first_listened = MIN(first_listened, excluded.first_listened), last_listened = MAX(last_listened, excluded.last_listened)",
+            rollup.table(),
+            rollup.period_start("$1")
+        ))
+        .bind(date)
+        .bind(song_id)
+        .bind(device)
+        .bind(seconds)
+        .bind(plays)
+        .bind(skips)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    async fn add_song(storage: &SqliteStorage, artist: &str, title: &str, album: &str) -> i64 {
+        let artist_id = storage.upsert_artist(artist).await.unwrap().id;
+        storage
+            .upsert_song(&NewSong {
+                title,
+                artist_id,
+                album: Some(album),
+                genre: Some("Rock"),
+                duration: Some(200),
+            })
+            .await
+            .unwrap()
+            .id
+    }
+
+    /// Play the song at noon of `day` for `seconds`
+    async fn listen(storage: &SqliteStorage, song_id: i64, day: &str, device: &str, seconds: u32) {
+        let started_at = date(day).and_hms_opt(12, 0, 0).unwrap();
+        let play = storage
+            .start_play(started_at, song_id, device)
+            .await
+            .unwrap();
+        storage.add_playback_time(&play, seconds).await.unwrap();
+    }
+
+    async fn fixture() -> SqliteStorage {
+        let storage = SqliteStorage::in_memory().await.unwrap();
+        let creep = add_song(&storage, "Radiohead", "Creep", "Pablo Honey").await;
+        let airbag = add_song(&storage, "Radiohead", "Airbag", "OK Computer").await;
+        let uprising = add_song(&storage, "Muse", "Uprising", "The Resistance").await;
+        listen(&storage, creep, "2024-01-31", "desk", 100).await;
+        listen(&storage, airbag, "2024-02-01", "desk", 50).await;
+        listen(&storage, uprising, "2024-02-15", "laptop", 300).await;
+        listen(&storage, creep, "2024-02-15", "laptop", 20).await;
+        storage
+    }
+
+    /// Period, name and time of every row `print` would show with `args`
+    async fn stats(storage: &SqliteStorage, args: &[&str]) -> Vec<(String, String, u32)> {
+        let command = PrintArgs::try_parse_from(["print"].iter().chain(args)).unwrap();
+        storage
+            .stats(&command)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| {
+                let name = [x.artist, x.title, x.device].into_iter().flatten();
+                (x.date, name.collect::<Vec<_>>().join(" "), x.time)
+            })
+            .collect()
+    }
+
+    fn names(rows: &[(String, String, u32)]) -> Vec<(&str, u32)> {
+        rows.iter()
+            .map(|(_, x, time)| (x.as_str(), *time))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn groups_by_fields() {
+        let storage = fixture().await;
+        assert_eq!(
+            names(&stats(&storage, &["-G", "artist", "-s", "time:desc"]).await),
+            [("Muse", 300), ("Radiohead", 170)]
+        );
+        assert_eq!(
+            names(
+                &stats(
+                    &storage,
+                    &["-G", "artist", "-G", "device", "-s", "time:desc"]
+                )
+                .await
+            ),
+            [
+                ("Muse laptop", 300),
+                ("Radiohead desk", 150),
+                ("Radiohead laptop", 20)
+            ]
+        );
+        assert_eq!(
+            names(&stats(&storage, &["-s", "title"]).await),
+            [
+                ("Radiohead Airbag", 50),
+                ("Radiohead Creep", 120),
+                ("Muse Uprising", 300)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_listens_only_for_songs_with_a_duration() {
+        let storage = fixture().await;
+        let artist_id = storage.upsert_artist("Muse").await.unwrap().id;
+        for (title, duration) in [("Intro", Some(0)), ("Outro", None)] {
+            let song_id = storage
+                .upsert_song(&NewSong {
+                    title,
+                    artist_id,
+                    album: None,
+                    genre: None,
+                    duration,
+                })
+                .await
+                .unwrap()
+                .id;
+            listen(&storage, song_id, "2024-02-15", "desk", 30).await;
+        }
+        let command = PrintArgs::try_parse_from(["print", "-s", "title"]).unwrap();
+        let listens = storage
+            .stats(&command)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.title.unwrap(), x.times_listened))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listens,
+            [
+                ("Airbag".into(), Some(0)),
+                ("Creep".into(), Some(0)),
+                ("Intro".into(), None),
+                ("Outro".into(), None),
+                ("Uprising".into(), Some(1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fills_in_the_defaults_of_constructed_queries() {
+        let storage = fixture().await;
+        let filters = PrintArgs::try_parse_from(["print"]).unwrap().filters;
+        let command = PrintArgs::new(filters, Vec::new(), Vec::new());
+        assert_eq!(
+            storage
+                .stats(&command)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|x| (x.title.unwrap(), x.time))
+                .collect::<Vec<_>>(),
+            [
+                ("Airbag".into(), 50),
+                ("Creep".into(), 120),
+                ("Uprising".into(), 300)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn filters_by_patterns_and_devices() {
+        async fn titles(storage: &SqliteStorage, args: &[&str]) -> Vec<String> {
+            let args = [args, &["-s", "title"]].concat();
+            stats(storage, &args)
+                .await
+                .into_iter()
+                .map(|(_, name, _)| name)
+                .collect()
+        }
+        let storage = fixture().await;
+        assert_eq!(
+            titles(&storage, &["--artist", "radio*"]).await,
+            ["Radiohead Airbag", "Radiohead Creep"]
+        );
+        assert_eq!(
+            titles(&storage, &["--title", "CREEP"]).await,
+            ["Radiohead Creep"]
+        );
+        assert_eq!(
+            titles(&storage, &["--not-album", "/^OK /"]).await,
+            ["Radiohead Creep", "Muse Uprising"]
+        );
+        assert_eq!(
+            titles(&storage, &["--genre", "rock", "--not-artist", "muse"]).await,
+            ["Radiohead Airbag", "Radiohead Creep"]
+        );
+        assert_eq!(
+            titles(&storage, &["--device", "laptop"]).await,
+            ["Radiohead Creep", "Muse Uprising"]
+        );
+    }
+
+    #[tokio::test]
+    async fn groups_by_time_within_date_bounds() {
+        let storage = fixture().await;
+        let by_month = [
+            "-g", "month", "-G", "artist", "-s", "period", "-s", "artist",
+        ];
+        assert_eq!(
+            stats(&storage, &by_month).await,
+            [
+                ("2024-01-01".into(), "Radiohead".into(), 100),
+                ("2024-02-01".into(), "Muse".into(), 300),
+                ("2024-02-01".into(), "Radiohead".into(), 70),
+            ]
+        );
+        // Whole months are read from the monthly rollup and the rest from the daily one, both
+        // have to agree with the listening times
+        for (args, expected) in [
+            (
+                &["--between", "2024-02-01", "2024-02-29"][..],
+                &[("Muse", 300), ("Radiohead", 70)][..],
+            ),
+            (
+                &["--between", "2024-02-01", "2024-02-14"],
+                &[("Radiohead", 50)],
+            ),
+            (
+                &["--after", "2024-02-01", "--exclusive"],
+                &[("Muse", 300), ("Radiohead", 20)],
+            ),
+            (&["--before", "2024-01-31"], &[("Radiohead", 100)]),
+        ] {
+            let args = [args, &["-G", "artist", "-s", "artist"]].concat();
+            assert_eq!(names(&stats(&storage, &args).await), expected, "{args:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_plays_by_time_of_day() {
+        let storage = fixture().await;
+        let after = |time| ["--after", time, "-G", "artist", "-s", "artist"];
+        assert_eq!(
+            names(&stats(&storage, &after("2024-02-15 12:00")).await),
+            [("Muse", 300), ("Radiohead", 20)]
+        );
+        assert!(stats(&storage, &after("2024-02-15T12:00:01"))
+            .await
+            .is_empty());
+        assert_eq!(
+            names(&stats(&storage, &["--before", "2024-02-01 12:00", "-s", "title"]).await),
+            [("Radiohead Airbag", 50), ("Radiohead Creep", 100)]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_rollups_in_sync() {
+        let storage = fixture().await;
+        let creep = add_song(&storage, "Radiohead", "Creep", "Pablo Honey").await;
+        let play = storage
+            .start_play(
+                date("2024-02-16").and_hms_opt(8, 0, 0).unwrap(),
+                creep,
+                "desk",
+            )
+            .await
+            .unwrap();
+        storage.skip_play(&play).await.unwrap();
+        for rollup in Rollup::ALL {
+            let difference = sqlx::query_scalar::<_, i64>(&format!(
+                "SELECT COUNT(*) FROM ({} EXCEPT SELECT date, song_id, device, playback_time, plays, skips, first_listened, last_listened FROM {})",
+                rollup.totals(),
+                rollup.table()
+            ))
+            .fetch_one(storage.pool())
+            .await
+            .unwrap();
+            assert_eq!(difference, 0, "{}", rollup.table());
+        }
+        let totals = sqlx::query_as::<_, (i64, i64, i64)>(
+            "SELECT SUM(playback_time), SUM(plays), SUM(skips) FROM rollup_years",
+        )
+        .fetch_one(storage.pool())
+        .await
+        .unwrap();
+        assert_eq!(totals, (470, 5, 1));
+    }
+
+    #[tokio::test]
+    async fn leaves_out_days_without_listening() {
+        let storage = fixture().await;
+        let creep = add_song(&storage, "Radiohead", "Creep", "Pablo Honey").await;
+        storage
+            .start_play(
+                date("2024-03-01").and_hms_opt(8, 0, 0).unwrap(),
+                creep,
+                "desk",
+            )
+            .await
+            .unwrap();
+        let filters = PrintArgs::try_parse_from(["print"]).unwrap().filters;
+        let summary = storage.summary(&filters).await.unwrap();
+        assert_eq!((summary.time, summary.days), (470, 3));
+        assert_eq!(
+            storage.daily_totals(&filters).await.unwrap(),
+            [
+                (date("2024-01-31"), 100),
+                (date("2024-02-01"), 50),
+                (date("2024-02-15"), 320)
+            ]
+        );
+    }
+}
@@ -1,22 +1,72 @@
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     fs::File,
-    io::{self, Write},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use chrono::{Datelike, Days, Months, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
 use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
-use serde_json::ser::Formatter;
 use sqlx::FromRow;
 
+use crate::storage::sql::{glob_to_regex, is_glob};
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    /// Database file to keep the stats in, or a postgres:// URL when built with the postgres
+    /// feature
+    #[arg(long, global = true, env = "MPDTRACKR_DB", value_name("PATH"))]
+    pub db: Option<String>,
+    /// Config file to use
+    #[arg(long, global = true, env = "MPDTRACKR_CONFIG", value_name("PATH"))]
+    pub config: Option<PathBuf>,
+    /// Use a separate database and config file for PROFILE
+    #[arg(long, global = true, env = "MPDTRACKR_PROFILE", value_name("PROFILE"))]
+    pub profile: Option<String>,
     #[command(subcommand)]
     pub subcommand: SubCommand,
 }
 
+impl Args {
+    /// Path of the database file, defaults to the data directory of the current profile
+    pub fn db_path(&self) -> Result<PathBuf> {
+        match &self.db {
+            Some(path) => Ok(path.into()),
+            None => Ok(dirs::data_dir()
+                .ok_or(anyhow!(
+                    "No data directory found! Use --db to choose where the database is kept"
+                ))?
+                .join(env!("CARGO_PKG_NAME"))
+                .join(self.profile_file_name(".db"))),
+        }
+    }
+
+    /// Path of the config file, defaults to the config directory of the current profile
+    pub fn config_path(&self) -> Result<PathBuf> {
+        match &self.config {
+            Some(path) => Ok(path.clone()),
+            None => Ok(dirs::config_dir()
+                .ok_or(anyhow!(
+                    "No config directory found! Use --config to choose where the config is kept"
+                ))?
+                .join(env!("CARGO_PKG_NAME"))
+                .join(self.profile_file_name("-config.json"))),
+        }
+    }
+
+    fn profile_file_name(&self, suffix: &str) -> String {
+        match &self.profile {
+            Some(profile) => format!("{}-{profile}{suffix}", env!("CARGO_PKG_NAME")),
+            None => format!("{}{suffix}", env!("CARGO_PKG_NAME")),
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum SubCommand {
     /// Run the daemon.
@@ -32,27 +82,79 @@ pub enum SubCommand {
     //     files: Vec<String>,
     // },
     /// Print listening stats to stdout with formatting options
-    Print(PrintArgs),
+    Print(Box<PrintArgs>),
+    /// Show a calendar of how much you listened on every day of a year
+    Heatmap(Box<HeatmapArgs>),
+    /// Compare the most listened entries of a period with the period before it or another
+    /// date range
+    Compare(Box<CompareArgs>),
+    /// Manage the database
+    Db(DbArgs),
+}
+
+#[derive(Debug, Parser)]
+pub struct DbArgs {
+    #[command(subcommand)]
+    pub subcommand: DbCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Apply pending schema migrations
+    Migrate {
+        /// Only show the pending migrations without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Look for orphaned listening times, songs without artists or titles and rollups that
+    /// disagree with the listening times
+    Check {
+        /// Give songs without an artist or title an Unknown one, remove listening times that
+        /// belong to no song and rebuild the rollups
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Safely copy the database to a timestamped file, even while the daemon is running
+    Backup {
+        /// Directory to put the backup in, defaults to a backups directory next to the database
+        path: Option<PathBuf>,
+        /// Number of backups to keep, older ones are deleted
+        #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+        keep: Option<u32>,
+    },
 }
 
-#[derive(Debug, ValueEnum, Clone)]
+#[derive(Debug, ValueEnum, Clone, Default)]
 pub enum TimeGroup {
     Day,
     Week,
     Month,
+    Quarter,
     Year,
+    Decade,
+    #[default]
     AllTime,
+    /// Hour of the day plays started in, listening times recorded before plays were logged are
+    /// left out
+    HourOfDay,
+    /// Day of the week, adding up every week
+    DayOfWeek,
+    /// Month of the year, adding up every year
+    MonthOfYear,
 }
 
-#[derive(Debug, ValueEnum, Clone)]
+#[derive(Debug, ValueEnum, Clone, Default)]
 pub enum FieldGroup {
     Album,
     Artist,
     Genre,
+    #[default]
     Title,
+    /// Device the listening time was recorded on
+    Device,
 }
 
-#[derive(Debug, ValueEnum, Clone)]
+#[derive(Debug, ValueEnum, Clone, Copy, Default)]
 pub enum SortBy {
     /// Album name
     Album,
@@ -63,17 +165,340 @@ pub enum SortBy {
     /// Song genre
     Genre,
     /// Time listened
+    #[default]
     Time,
     /// Most recently listened
     Recent,
+    /// Date first listened
+    FirstListened,
+    /// Times listened, only known when grouping by title
+    TimesListened,
+    /// Song duration
+    Duration,
+    /// Number of times songs were started
+    PlayCount,
+    /// Share of plays that were skipped before reaching half of the song
+    SkipRate,
+    /// Time group, from the earliest to the latest
+    Period,
 }
 
-#[derive(Debug, Parser)]
+/// Key to sort entries by and which way, written as `key[:asc|:desc]`
+#[derive(Debug, Clone, Copy)]
+pub struct SortKey {
+    pub by: SortBy,
+    pub descending: bool,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One line per entry
+    #[default]
+    Text,
+    /// Aligned columns that fit the terminal
+    Table,
+    Json,
+    /// One JSON object per line, printed as soon as it is read
+    Ndjson,
+    /// Comma separated values with a header row and times in seconds
+    Csv,
+    /// Tab separated values with a header row and times in seconds
+    Tsv,
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy)]
+pub enum ChartStyle {
+    /// A bar for every entry
+    Bars,
+    /// A line for each of the --limit (10 by default) most listened entries showing their
+    /// listening time in every time group
+    Sparklines,
+}
+
+/// How listening times are written out
+#[derive(Debug, ValueEnum, Clone, Copy, Default)]
+pub enum DurationFormat {
+    /// Like 13h14m24s
+    #[default]
+    Hms,
+    /// Number of seconds
+    Seconds,
+    /// Hours with one decimal, like 13.2
+    Hours,
+    /// The two largest units, like 13h 14m or 5d 21h
+    Human,
+}
+
+impl DurationFormat {
+    pub fn format(self, seconds: u64) -> String {
+        match self {
+            DurationFormat::Hms => hms(seconds),
+            DurationFormat::Seconds => seconds.to_string(),
+            DurationFormat::Hours => format!("{:.1}", seconds as f64 / 3600.0),
+            DurationFormat::Human => {
+                let units = [
+                    (seconds / 86400, "d"),
+                    (seconds % 86400 / 3600, "h"),
+                    (seconds % 3600 / 60, "m"),
+                    (seconds % 60, "s"),
+                ];
+                match units.iter().position(|(x, _)| *x > 0) {
+                    Some(i) => units[i..]
+                        .iter()
+                        .take(2)
+                        .filter(|(x, _)| *x > 0)
+                        .map(|(x, unit)| format!("{x}{unit}"))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    None => "0s".to_owned(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, Default)]
+pub enum ColorChoice {
+    /// Color when printing to a terminal and NO_COLOR isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Ways of splitting the year into weeks
+#[derive(Debug, ValueEnum, Clone, Copy, Default)]
+pub enum WeekStyle {
+    /// ISO 8601 weeks, starting on Monday with week 1 holding the first Thursday of the year
+    #[default]
+    Iso,
+    /// Weeks starting on Monday, numbered like strftime's %W
+    Monday,
+    /// Weeks starting on Sunday, numbered like strftime's %U
+    Sunday,
+}
+
+/// Calendar periods relative to today
+#[derive(Debug, ValueEnum, Clone, Copy)]
+pub enum Period {
+    Today,
+    Yesterday,
+    ThisWeek,
+    LastWeek,
+    ThisMonth,
+    LastMonth,
+    ThisYear,
+    LastYear,
+    /// From the start of this year up to and including today
+    Ytd,
+}
+
+/// Length of time counted back from a date, such as 7d or 3 months
+#[derive(Debug, Clone, Copy)]
+pub struct Span {
+    amount: u32,
+    unit: SpanUnit,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SpanUnit {
+    Days,
+    Weeks,
+    Months,
+    Years,
+}
+
+/// Date given to --after or --before, with an optional time of day that only the play log can
+/// be filtered by
+#[derive(Debug, Clone, Copy)]
+pub struct DateBound {
+    pub date: NaiveDate,
+    pub time: Option<NaiveTime>,
+}
+
+/// Date that is either given as is or relative to today
+#[derive(Debug, Clone, Copy)]
+pub enum RelativeDate {
+    Date(NaiveDate),
+    Ago(Span),
+}
+
+const PATTERN_HELP: &str = "\
+A PATTERN matches values that are equal to it, ignoring case. Patterns containing *, ? or [...] \
+are matched as globs, and patterns wrapped in slashes such as /^The .*s$/ as regular expressions.";
+
+#[derive(Debug, Clone, Parser)]
+#[command(after_help = PATTERN_HELP)]
 pub struct PrintArgs {
-    /// Output data in json format
-    #[arg(short, long)]
+    /// Output data in json format, same as --format json
+    #[arg(short, long, conflicts_with = "format")]
     pub json: bool,
-    /// Only print stats between a start DATE and end DATE
+    /// Output format, defaults to table when printing to a terminal and text otherwise
+    #[arg(short, long)]
+    pub format: Option<OutputFormat>,
+    /// Comma separated COLUMNS to show in table, CSV and TSV output, e.g. title,artist,time,plays
+    #[arg(long, value_delimiter = ',', value_name("COLUMNS"), value_parser = parse_column)]
+    pub columns: Vec<&'static str>,
+    /// When to color table output
+    #[arg(long, value_name("WHEN"), default_value = "auto")]
+    pub color: ColorChoice,
+    /// How to write listening times, defaults to seconds for CSV and TSV and hms otherwise
+    #[arg(long, value_name("FORMAT"))]
+    pub duration_format: Option<DurationFormat>,
+    /// Print totals for everything that matches the filters after the entries
+    #[arg(long)]
+    pub summary: bool,
+    /// Print every entry using TEMPLATE, e.g. '{rank}. {artist} – {title} ({time:hms})', or the
+    /// template with that name in the config file
+    #[arg(short, long, value_name("TEMPLATE"), conflicts_with_all = ["json", "format"])]
+    pub template: Option<String>,
+    /// Draw a chart of the listening times instead of listing the entries
+    #[arg(long, value_name("STYLE"), conflicts_with_all = ["json", "format", "template"])]
+    pub chart: Option<ChartStyle>,
+    /// Only use ASCII characters in charts, which is the default if the locale isn't UTF-8
+    #[arg(long)]
+    pub ascii: bool,
+    #[command(flatten)]
+    pub filters: Filters,
+    /// How weeks are split and numbered, defaults to iso unless week_start in the config file is
+    /// Sunday. Also picks the first day of the week for --period.
+    #[arg(long, value_name("STYLE"))]
+    pub weeks: Option<WeekStyle>,
+    /// Group listening times by given time frame
+    #[arg(short = 'g', long, default_value = "all-time")]
+    pub time_group: Option<TimeGroup>,
+    /// Group listening times by given field, can be given multiple times to group by several
+    /// fields at once
+    #[arg(short = 'G', long, default_value = "title")]
+    pub field_group: Vec<FieldGroup>,
+    /// Sort entries by KEY, ascending unless followed by :desc (e.g. time:desc), can be given
+    /// multiple times. KEY is one of album, artist, title, genre, time, recent, first-listened,
+    /// times-listened, duration, play-count, skip-rate or period
+    #[arg(short, long, default_value = "time", value_name("KEY"))]
+    pub sort: Vec<SortKey>,
+    /// Print entries in the opposite order
+    #[arg(short, long)]
+    pub reverse: bool,
+    /// Only print the N highest ranked entries
+    #[arg(short = 'n', long, value_name("N"))]
+    pub limit: Option<u32>,
+    /// Only print the N highest ranked entries of every time group
+    #[arg(long, value_name("N"))]
+    pub top_per_group: Option<u32>,
+    /// Also show the share of the entries ranked up to and including each entry
+    #[arg(long)]
+    pub cumulative: bool,
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(after_help = PATTERN_HELP)]
+pub struct HeatmapArgs {
+    /// Year to show, defaults to the dates given to the date options if they have a start and
+    /// an end, or the last year otherwise
+    #[arg(short, long)]
+    pub year: Option<i32>,
+    /// Only use ASCII characters, which is the default if the locale isn't UTF-8
+    #[arg(long)]
+    pub ascii: bool,
+    #[command(flatten)]
+    pub filters: Filters,
+}
+
+impl HeatmapArgs {
+    /// First and last day the calendar shows. Since the calendar is padded to whole weeks,
+    /// the week around either end has to exist as well.
+    pub fn range(&self, today: NaiveDate) -> Result<(NaiveDate, NaiveDate)> {
+        let padded = |start: NaiveDate, end: NaiveDate| {
+            let week = Days::new(6);
+            start.checked_sub_days(week).is_some() && end.checked_add_days(week).is_some()
+        };
+        if let Some(year) = self.year {
+            let start = NaiveDate::from_ymd_opt(year, 1, 1);
+            let end = NaiveDate::from_ymd_opt(year, 12, 31);
+            return start
+                .zip(end)
+                .filter(|(start, end)| padded(*start, *end))
+                .ok_or_else(|| anyhow!("Year {year} is out of range"));
+        }
+        let (start, end) = match self.filters.date_bounds() {
+            (Some(start), Some(end)) if start <= end => (start, end),
+            _ => (
+                today
+                    .checked_sub_months(Months::new(12))
+                    .and_then(|x| x.succ_opt())
+                    .ok_or_else(|| anyhow!("Date range reaches too far back"))?,
+                today,
+            ),
+        };
+        if !padded(start, end) {
+            bail!("Dates from {start} to {end} are out of range");
+        }
+        Ok((start, end))
+    }
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(after_help = PATTERN_HELP)]
+pub struct CompareArgs {
+    /// Compare with the listening between a start DATE and end DATE, both included, instead of
+    /// the period right before
+    #[arg(long, number_of_values = 2, value_name("DATE"))]
+    pub against: Option<Vec<NaiveDate>>,
+    /// Group listening times by given field, can be given multiple times to group by several
+    /// fields at once
+    #[arg(short = 'G', long, default_value = "artist")]
+    pub field_group: Vec<FieldGroup>,
+    /// Number of entries to show
+    #[arg(short = 'n', long, value_name("N"), default_value_t = 20)]
+    pub limit: u32,
+    /// How to write listening times
+    #[arg(long, value_name("FORMAT"), default_value = "hms")]
+    pub duration_format: DurationFormat,
+    /// Only use ASCII characters, which is the default if the locale isn't UTF-8
+    #[arg(long)]
+    pub ascii: bool,
+    #[command(flatten)]
+    pub filters: Filters,
+}
+
+impl CompareArgs {
+    /// The date range to look at and the one to compare it with. Named periods are compared
+    /// with the one before them, e.g. this-month with last month, other ranges with the same
+    /// number of days right before them.
+    pub fn ranges(
+        &self,
+        today: NaiveDate,
+        week_start: Weekday,
+    ) -> Result<((NaiveDate, NaiveDate), (NaiveDate, NaiveDate))> {
+        let (Some(start), end) = self.filters.date_bounds() else {
+            return Err(anyhow!(
+                "Pick the dates to compare with --period, --last, --since, --after or --between"
+            ));
+        };
+        let end = end.unwrap_or(today);
+        if end < start {
+            return Err(anyhow!("The dates to compare don't contain any days"));
+        }
+        let previous = match (self.against.as_deref(), self.filters.period) {
+            (Some([start, end]), _) => (*start, *end),
+            (_, Some(period)) => period.previous(today, week_start),
+            _ => {
+                let days = Days::new((end - start).num_days() as u64 + 1);
+                let out_of_range = || anyhow!("Date range reaches too far back");
+                (
+                    start.checked_sub_days(days).ok_or_else(out_of_range)?,
+                    start.pred_opt().ok_or_else(out_of_range)?,
+                )
+            }
+        };
+        Ok(((start, end), previous))
+    }
+}
+
+/// Options choosing which listening times are counted, shared by the subcommands that show stats
+#[derive(Debug, Clone, clap::Args)]
+pub struct Filters {
+    /// Only count listening between a start DATE and end DATE, both included
     /// Dates should be in Y-M-D format
     // A tuple would be better for this but that doesn't work in clap yet
     #[arg(
@@ -84,21 +509,91 @@ pub struct PrintArgs {
         value_name("DATE")
     )]
     pub between: Option<Vec<chrono::NaiveDate>>,
-    /// Only print stats before DATE
-    #[arg(short = 'b', long, group = "range", value_name("DATE"))]
-    pub before: Option<chrono::NaiveDate>,
-    /// Only print stats after DATE
-    #[arg(short = 'a', long, group = "range", value_name("DATE"))]
-    pub after: Option<chrono::NaiveDate>,
-    /// Group listening times by given time frame
-    #[arg(short = 'g', long, default_value = "all-time")]
-    pub time_group: Option<TimeGroup>,
-    /// Group listening times given field
-    #[arg(short = 'G', long, default_value = "title")]
-    pub field_group: Option<FieldGroup>,
-    /// Sort entries by given option
-    #[arg(short, long, default_value = "time")]
-    pub sort: Vec<SortBy>,
+    /// Only count listening up to and including DATE, can be combined with the other date options.
+    /// A time can be added like "2024-03-01 18:00" to only count plays started up to then.
+    #[arg(short = 'b', long, value_name("DATE"))]
+    pub before: Option<DateBound>,
+    /// Only count listening from DATE onwards, can be combined with the other date options. A time
+    /// can be added like "2024-03-01 18:00" to only count plays started from then on.
+    #[arg(short = 'a', long, value_name("DATE"))]
+    pub after: Option<DateBound>,
+    /// Leave out the dates given to --after, --before and --between themselves
+    #[arg(long, conflicts_with_all = ["last", "since", "period"])]
+    pub exclusive: bool,
+    /// Only count listening from the last SPAN up to and including today, e.g. 7d, 2w or 3months
+    #[arg(long, group = "range", value_name("SPAN"))]
+    pub last: Option<Span>,
+    /// Only count listening from WHEN up to and including today, e.g. "2 weeks ago", yesterday
+    /// or a Y-M-D date
+    #[arg(long, group = "range", value_name("WHEN"))]
+    pub since: Option<RelativeDate>,
+    /// Only count listening during a named PERIOD, weeks start on the week_start day in the
+    /// config file
+    #[arg(long, group = "range")]
+    pub period: Option<Period>,
+    /// Only count listening recorded on DEVICE, can be given multiple times
+    #[arg(short, long, value_name("DEVICE"))]
+    pub device: Vec<String>,
+    /// Only count listening to artists matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub artist: Vec<String>,
+    /// Leave out listening to artists matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub not_artist: Vec<String>,
+    /// Only count listening to albums matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub album: Vec<String>,
+    /// Leave out listening to albums matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub not_album: Vec<String>,
+    /// Only count listening to genres matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub genre: Vec<String>,
+    /// Leave out listening to genres matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub not_genre: Vec<String>,
+    /// Only count listening to song titles matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub title: Vec<String>,
+    /// Leave out listening to song titles matching PATTERN, can be given multiple times
+    #[arg(long, value_name("PATTERN"), value_parser = parse_pattern)]
+    pub not_title: Vec<String>,
+}
+
+impl SortBy {
+    /// Whether the top ranked entries have the largest values for this key, which goes for
+    /// everything but names and periods
+    pub fn ranks_descending(&self) -> bool {
+        !matches!(
+            self,
+            SortBy::Album | SortBy::Artist | SortBy::Title | SortBy::Genre | SortBy::Period
+        )
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, direction) = s.split_once(':').unwrap_or((s, "asc"));
+        let by = SortBy::from_str(key, true).map_err(|_| {
+            format!(
+                "unknown sort key '{key}', use one of {}",
+                SortBy::value_variants()
+                    .iter()
+                    .filter_map(|x| x.to_possible_value())
+                    .map(|x| x.get_name().to_owned())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })?;
+        let descending = match direction.to_lowercase().as_str() {
+            "asc" => false,
+            "desc" => true,
+            _ => return Err(format!("unknown direction '{direction}', use asc or desc")),
+        };
+        Ok(SortKey { by, descending })
+    }
 }
 
 impl Display for SortBy {
@@ -107,17 +602,45 @@ impl Display for SortBy {
             f,
             "{}",
             match self {
-                SortBy::Album => "songs.album",
-                SortBy::Artist => "artists.name",
-                SortBy::Title => "songs.title",
-                SortBy::Genre => "songs.genre",
+                SortBy::Album => "album",
+                SortBy::Artist => "artist",
+                SortBy::Title => "title",
+                SortBy::Genre => "genre",
                 SortBy::Time => "time",
-                SortBy::Recent => "listening_times.date",
+                SortBy::Recent => "last_listened",
+                SortBy::FirstListened => "first_listened",
+                SortBy::TimesListened => "times_listened",
+                SortBy::Duration => "duration",
+                SortBy::PlayCount => "play_count",
+                SortBy::SkipRate => "skip_rate",
+                SortBy::Period => "date",
             }
         )
     }
 }
 
+impl FieldGroup {
+    /// Columns of [`DataRow`] that have a single value for every group of this field
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            FieldGroup::Album => &["album"],
+            FieldGroup::Artist => &["artist", "artist_id"],
+            FieldGroup::Genre => &["genre"],
+            FieldGroup::Title => &[
+                "title",
+                "song_id",
+                "duration",
+                "times_listened",
+                "artist",
+                "artist_id",
+                "album",
+                "genre",
+            ],
+            FieldGroup::Device => &["device"],
+        }
+    }
+}
+
 impl Display for FieldGroup {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -125,42 +648,48 @@ impl Display for FieldGroup {
             "{}",
             match self {
                 FieldGroup::Album => "songs.album",
-                FieldGroup::Artist => "artists.name",
-                FieldGroup::Title => "songs.title",
+                FieldGroup::Artist => "artists.id",
+                // Different artists can have songs with the same title
+                FieldGroup::Title => "songs.id",
                 FieldGroup::Genre => "songs.genre",
+                FieldGroup::Device => "listening_times.device",
             }
         )
     }
 }
 
-impl Default for FieldGroup {
-    fn default() -> Self {
-        Self::Title
-    }
-}
-
-impl Default for SortBy {
-    fn default() -> Self {
-        Self::Time
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub mpd_url: Box<str>,
     pub mpd_port: u16,
+    /// Name stored with every listening time so that databases shared between
+    /// machines can tell which machine recorded what
+    #[serde(default = "default_device_id")]
+    pub device_id: Box<str>,
+    /// Back up the database once a day while the daemon is running
+    #[serde(default)]
+    pub daily_backup: bool,
+    /// Directory backups are put in, defaults to a backups directory next to the database
+    #[serde(default)]
+    pub backup_dir: Option<PathBuf>,
+    /// Number of backups to keep, older ones are deleted
+    #[serde(default = "default_backup_keep")]
+    pub backup_keep: u32,
+    /// First day of the week for periods like this-week
+    #[serde(default = "default_week_start")]
+    pub week_start: Weekday,
+    /// Templates that can be passed to `print --template` by name
+    #[serde(default = "default_templates")]
+    pub templates: BTreeMap<String, String>,
 }
 
 impl Config {
-    pub fn from_config_file() -> Result<Self> {
-        let config = dirs::config_dir()
-            .ok_or(anyhow!("No config directory found!"))?
-            .join(env!("CARGO_PKG_NAME"))
-            .join(concat!(env!("CARGO_PKG_NAME"), "-config.json"));
+    /// Read the config file at `config`, creating it with the default settings if it's missing
+    pub fn from_config_file(config: &Path) -> Result<Self> {
         if !config.is_file() {
             std::fs::create_dir_all(config.parent().expect("Config file should have parent dir"))?;
             write!(
-                File::create(&config)?,
+                File::create(config)?,
                 "{}",
                 serde_json::to_string_pretty(&Config::new())?
             )?;
@@ -175,10 +704,381 @@ impl Config {
         Config {
             mpd_url: "127.0.0.1".into(),
             mpd_port: 6600,
+            device_id: default_device_id(),
+            daily_backup: false,
+            backup_dir: None,
+            backup_keep: default_backup_keep(),
+            week_start: default_week_start(),
+            templates: default_templates(),
+        }
+    }
+}
+
+impl PrintArgs {
+    /// Query for everything the `filters` match grouped by `field_group` over all time, with
+    /// every other option left at what print uses when it isn't given. Like print, entries are
+    /// grouped by title and sorted by time if `field_group` or `sort` are empty.
+    pub fn new(filters: Filters, mut field_group: Vec<FieldGroup>, mut sort: Vec<SortKey>) -> Self {
+        if field_group.is_empty() {
+            field_group.push(FieldGroup::Title);
+        }
+        if sort.is_empty() {
+            sort.push(SortKey {
+                by: SortBy::Time,
+                descending: false,
+            });
+        }
+        Self {
+            json: false,
+            format: None,
+            columns: Vec::new(),
+            color: ColorChoice::Auto,
+            duration_format: None,
+            summary: false,
+            template: None,
+            chart: None,
+            ascii: false,
+            filters,
+            weeks: None,
+            time_group: Some(TimeGroup::AllTime),
+            field_group,
+            sort,
+            reverse: false,
+            limit: None,
+            top_per_group: None,
+            cumulative: false,
+        }
+    }
+
+    /// Resolve the relative date options of the filters, and pick the week style matching
+    /// `week_start` if none was given
+    pub fn resolve_dates(&mut self, today: NaiveDate, week_start: Weekday) -> Result<()> {
+        let week_start = self.weeks.map_or(week_start, |x| x.first_day());
+        self.weeks.get_or_insert(match week_start {
+            Weekday::Sun => WeekStyle::Sunday,
+            _ => WeekStyle::Iso,
+        });
+        self.filters.resolve_dates(today, week_start)
+    }
+
+    /// Replace a template name with the template of that name in `templates`
+    pub fn resolve_template(&mut self, templates: &BTreeMap<String, String>) -> Result<()> {
+        let Some(template) = &mut self.template else {
+            return Ok(());
+        };
+        if let Some(named) = templates.get(template.as_str()) {
+            *template = named.clone();
+        } else if !template.contains('{') {
+            // Without any fields this is much more likely a misspelled name than a template
+            return Err(anyhow!(
+                "Unknown template '{template}', the config file has {}",
+                match templates.is_empty() {
+                    true => "none".to_owned(),
+                    false => templates.keys().cloned().collect::<Vec<_>>().join(", "),
+                }
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Filters {
+    /// Replace the relative date options with the absolute range they stand for, so that only
+    /// `after`, `before` and `between` have to be looked at when building queries
+    pub fn resolve_dates(&mut self, today: NaiveDate, week_start: Weekday) -> Result<()> {
+        let out_of_range = || anyhow!("Date range reaches too far back");
+        let range = match (self.last, self.since, self.period) {
+            (Some(last), _, _) => Some((
+                last.before(today)
+                    .and_then(|x| x.succ_opt())
+                    .ok_or_else(out_of_range)?,
+                today,
+            )),
+            (_, Some(since), _) => Some((since.resolve(today).ok_or_else(out_of_range)?, today)),
+            (_, _, Some(period)) => Some(period.range(today, week_start)),
+            _ => None,
+        };
+        if let Some((start, end)) = range {
+            self.between = Some(vec![start, end]);
+        }
+        Ok(())
+    }
+
+    /// The same filters over the dates from `start` to `end` instead, both included
+    pub fn with_range(&self, (start, end): (NaiveDate, NaiveDate)) -> Self {
+        Filters {
+            between: Some(vec![start, end]),
+            before: None,
+            after: None,
+            exclusive: false,
+            last: None,
+            since: None,
+            period: None,
+            ..self.clone()
+        }
+    }
+
+    /// First and last date to print stats for, both included. Every date option narrows the
+    /// range down further.
+    pub fn date_bounds(&self) -> (Option<NaiveDate>, Option<NaiveDate>) {
+        let (start, end) = match self.between.as_deref() {
+            Some([start, end]) => (Some(*start), Some(*end)),
+            _ => (None, None),
+        };
+        let shift = |date: NaiveDate, next: fn(&NaiveDate) -> Option<NaiveDate>| {
+            if self.exclusive {
+                next(&date).unwrap_or(date)
+            } else {
+                date
+            }
+        };
+        // Bounds with a time of day leave out part of their date, which is up to the play log
+        let start = [
+            start.map(|x| shift(x, NaiveDate::succ_opt)),
+            self.after.map(|x| match x.time {
+                Some(_) => x.date,
+                None => shift(x.date, NaiveDate::succ_opt),
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .max();
+        let end = [
+            end.map(|x| shift(x, NaiveDate::pred_opt)),
+            self.before.map(|x| match x.time {
+                Some(_) => x.date,
+                None => shift(x.date, NaiveDate::pred_opt),
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        (start, end)
+    }
+
+    /// Times the plays have to have started between, for --after and --before given with a
+    /// time of day
+    pub fn time_bounds(&self) -> (Option<NaiveDateTime>, Option<NaiveDateTime>) {
+        (
+            self.after.and_then(|x| x.datetime()),
+            self.before.and_then(|x| x.datetime()),
+        )
+    }
+
+    /// Whether only the play log can tell which listening times are counted
+    pub fn has_times(&self) -> bool {
+        self.time_bounds() != (None, None)
+    }
+}
+
+impl Period {
+    /// First and last day of the period that `today` falls in
+    pub fn range(&self, today: NaiveDate, week_start: Weekday) -> (NaiveDate, NaiveDate) {
+        let week = today
+            - Days::new(
+                ((7 + today.weekday().num_days_from_monday() - week_start.num_days_from_monday())
+                    % 7)
+                .into(),
+            );
+        let month = today.with_day(1).expect("Every month has a first day");
+        let year = today.with_ordinal(1).expect("Every year has a first day");
+        let day = Days::new(1);
+        match self {
+            Period::Today => (today, today),
+            Period::Yesterday => (today - day, today - day),
+            Period::ThisWeek => (week, week + Days::new(6)),
+            Period::LastWeek => (week - Days::new(7), week - day),
+            Period::ThisMonth => (month, month + Months::new(1) - day),
+            Period::LastMonth => (month - Months::new(1), month - day),
+            Period::ThisYear => (year, year + Months::new(12) - day),
+            Period::LastYear => (year - Months::new(12), year - day),
+            Period::Ytd => (year, today),
+        }
+    }
+
+    /// First and last day of the period before the one `today` falls in, e.g. the month before
+    /// last month. The year to date is compared with the same days of last year.
+    pub fn previous(&self, today: NaiveDate, week_start: Weekday) -> (NaiveDate, NaiveDate) {
+        let (start, end) = self.range(today, week_start);
+        let before = start - Days::new(1);
+        match self {
+            Period::Today | Period::Yesterday => Period::Today.range(before, week_start),
+            Period::ThisWeek | Period::LastWeek => Period::ThisWeek.range(before, week_start),
+            Period::ThisMonth | Period::LastMonth => Period::ThisMonth.range(before, week_start),
+            Period::ThisYear | Period::LastYear => Period::ThisYear.range(before, week_start),
+            Period::Ytd => (start - Months::new(12), end - Months::new(12)),
+        }
+    }
+}
+
+impl Span {
+    /// The date this span before `date`, or `None` if that is out of range
+    pub fn before(&self, date: NaiveDate) -> Option<NaiveDate> {
+        match self.unit {
+            SpanUnit::Days => date.checked_sub_days(Days::new(self.amount.into())),
+            SpanUnit::Weeks => date.checked_sub_days(Days::new(u64::from(self.amount) * 7)),
+            SpanUnit::Months => date.checked_sub_months(Months::new(self.amount)),
+            SpanUnit::Years => date.checked_sub_months(Months::new(self.amount.checked_mul(12)?)),
+        }
+    }
+}
+
+impl FromStr for Span {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.find(|x: char| !x.is_ascii_digit()).unwrap_or(s.len());
+        let amount = match s[..digits].parse() {
+            Ok(0) => return Err("the amount has to be at least 1".into()),
+            Ok(amount) => amount,
+            Err(_) => return Err(format!("'{s}' should start with a number, e.g. 7d")),
+        };
+        let unit = match s[digits..].trim().to_lowercase().as_str() {
+            "d" | "day" | "days" => SpanUnit::Days,
+            "w" | "week" | "weeks" => SpanUnit::Weeks,
+            "m" | "month" | "months" => SpanUnit::Months,
+            "y" | "year" | "years" => SpanUnit::Years,
+            unit => {
+                return Err(format!(
+                    "unknown unit '{unit}', use days (d), weeks (w), months (m) or years (y)"
+                ))
+            }
+        };
+        Ok(Span { amount, unit })
+    }
+}
+
+impl DateBound {
+    pub fn datetime(&self) -> Option<NaiveDateTime> {
+        self.time.map(|x| self.date.and_time(x))
+    }
+}
+
+impl FromStr for DateBound {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(date) = s.parse() {
+            return Ok(DateBound { date, time: None });
+        }
+        [
+            "%Y-%m-%d %H:%M:%S",
+            "%Y-%m-%dT%H:%M:%S",
+            "%Y-%m-%d %H:%M",
+            "%Y-%m-%dT%H:%M",
+        ]
+        .iter()
+        .find_map(|x| NaiveDateTime::parse_from_str(s, x).ok())
+        .map(|x| DateBound {
+            date: x.date(),
+            time: Some(x.time()),
+        })
+        .ok_or_else(|| {
+            format!("'{s}' should be a Y-M-D date, optionally followed by a time like 18:00")
+        })
+    }
+}
+
+impl RelativeDate {
+    pub fn resolve(&self, today: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            RelativeDate::Date(date) => Some(*date),
+            RelativeDate::Ago(span) => span.before(today),
+        }
+    }
+}
+
+impl FromStr for RelativeDate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_lowercase().as_str() {
+            "today" => Ok(RelativeDate::Ago(Span {
+                amount: 0,
+                unit: SpanUnit::Days,
+            })),
+            "yesterday" => Ok(RelativeDate::Ago(Span {
+                amount: 1,
+                unit: SpanUnit::Days,
+            })),
+            lower => match lower.strip_suffix("ago") {
+                Some(span) => Ok(RelativeDate::Ago(span.parse()?)),
+                None => s.parse().map(RelativeDate::Date).map_err(|_| {
+                    format!("'{s}' should be a Y-M-D date, today, yesterday or e.g. '2 weeks ago'")
+                }),
+            },
         }
     }
 }
 
+/// Accept the names in [`DataRow::COLUMNS`], and plays as the short form of play_count
+pub(crate) fn parse_column(column: &str) -> Result<&'static str, String> {
+    let column = column.trim().to_lowercase().replace('-', "_");
+    let column = match column.as_str() {
+        "plays" => "play_count",
+        column => column,
+    };
+    DataRow::COLUMNS
+        .iter()
+        .find(|x| **x == column)
+        .copied()
+        .ok_or_else(|| {
+            format!(
+                "unknown column '{column}', use one of {}",
+                DataRow::COLUMNS.join(", ")
+            )
+        })
+}
+
+/// Format seconds like 1h2m3s
+pub(crate) fn hms(seconds: u64) -> String {
+    format!(
+        "{}h{}m{}s",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+/// Format a fraction like 12.3%, empty if it is missing
+fn percentage(fraction: Option<f64>) -> String {
+    fraction
+        .map(|x| format!("{:.1}%", x * 100.0))
+        .unwrap_or_default()
+}
+
+/// Make sure regex and glob patterns compile so that a typo doesn't fail on every row
+fn parse_pattern(pattern: &str) -> Result<String> {
+    if let Some(regex) = pattern.strip_prefix('/').and_then(|x| x.strip_suffix('/')) {
+        regex::Regex::new(regex)?;
+    } else if is_glob(pattern) {
+        regex::Regex::new(&glob_to_regex(pattern)).map_err(|e| anyhow!("invalid glob: {e}"))?;
+    }
+    Ok(pattern.to_owned())
+}
+
+fn default_backup_keep() -> u32 {
+    7
+}
+
+fn default_week_start() -> Weekday {
+    Weekday::Mon
+}
+
+fn default_templates() -> BTreeMap<String, String> {
+    BTreeMap::from([(
+        "status".to_owned(),
+        "{artist} – {title} ({time:hms})".to_owned(),
+    )])
+}
+
+fn default_device_id() -> Box<str> {
+    gethostname::gethostname().to_string_lossy().into()
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self::new()
@@ -206,24 +1106,150 @@ pub struct DataRow {
     pub last_listened: chrono::NaiveDate,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub times_listened: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
     pub date: String,
+    pub play_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_rate: Option<f64>,
+    /// Fraction of the listening time of the time group, or of everything for all-time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share: Option<f64>,
+    /// Fraction of the listening time of the time group that went to this entry and the ones
+    /// ranked above it, only with --cumulative
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cumulative_share: Option<f64>,
+    /// Position of the entry within its time group when ranked by the sort keys, 1 being the
+    /// most listened, most recent or alphabetically first entry
+    pub rank: u32,
+}
+
+impl DataRow {
+    /// Every column in the order they are written in CSV and TSV output
+    pub const COLUMNS: &'static [&'static str] = &[
+        "rank",
+        "artist_id",
+        "song_id",
+        "title",
+        "artist",
+        "album",
+        "genre",
+        "duration",
+        "device",
+        "time",
+        "share",
+        "cumulative_share",
+        "date",
+        "times_listened",
+        "play_count",
+        "skip_rate",
+        "first_listened",
+        "last_listened",
+    ];
+
+    /// Header shown above a column in table output
+    pub fn header(column: &str) -> &'static str {
+        match column {
+            "rank" => "Rank",
+            "artist_id" => "Artist ID",
+            "song_id" => "Song ID",
+            "title" => "Title",
+            "artist" => "Artist",
+            "album" => "Album",
+            "genre" => "Genre",
+            "duration" => "Duration",
+            "device" => "Device",
+            "time" => "Listening Time",
+            "date" => "Date",
+            "times_listened" => "Times Listened",
+            "play_count" => "Plays",
+            "skip_rate" => "Skip Rate",
+            "share" => "Share",
+            "cumulative_share" => "Cumulative",
+            "first_listened" => "First Listened",
+            "last_listened" => "Last Listened",
+            _ => "",
+        }
+    }
+
+    /// Whether the column holds numbers, which are right aligned in tables
+    pub fn is_numeric(column: &str) -> bool {
+        matches!(
+            column,
+            "rank"
+                | "artist_id"
+                | "song_id"
+                | "duration"
+                | "time"
+                | "times_listened"
+                | "play_count"
+                | "skip_rate"
+                | "share"
+                | "cumulative_share"
+        )
+    }
+
+    /// Value of one of the [`Self::COLUMNS`] formatted like the text output, empty if it is
+    /// unknown
+    pub fn display_value(&self, column: &str, durations: DurationFormat) -> String {
+        match column {
+            "time" => durations.format(self.time.into()),
+            "skip_rate" => self
+                .skip_rate
+                .map(|x| format!("{:.0}%", x * 100.0))
+                .unwrap_or_default(),
+            "share" => percentage(self.share),
+            "cumulative_share" => percentage(self.cumulative_share),
+            // Plays weren't counted by older versions
+            "play_count" if self.play_count == 0 => String::new(),
+            column => self.value(column),
+        }
+    }
+
+    /// Raw value of one of the [`Self::COLUMNS`], empty if it is unknown
+    pub fn value(&self, column: &str) -> String {
+        fn or_empty<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(|x| x.to_string()).unwrap_or_default()
+        }
+        match column {
+            "rank" => self.rank.to_string(),
+            "artist_id" => or_empty(&self.artist_id),
+            "song_id" => or_empty(&self.song_id),
+            "title" => or_empty(&self.title),
+            "artist" => or_empty(&self.artist),
+            "album" => or_empty(&self.album),
+            "genre" => or_empty(&self.genre),
+            "duration" => or_empty(&self.duration),
+            "device" => or_empty(&self.device),
+            "time" => self.time.to_string(),
+            "date" => self.date.clone(),
+            "times_listened" => or_empty(&self.times_listened),
+            "play_count" => self.play_count.to_string(),
+            "skip_rate" => or_empty(&self.skip_rate),
+            "share" => or_empty(&self.share),
+            "cumulative_share" => or_empty(&self.cumulative_share),
+            "first_listened" => self.first_listened.to_string(),
+            "last_listened" => self.last_listened.to_string(),
+            _ => String::new(),
+        }
+    }
 }
 
 impl Display for DataRow {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let time = {
-            format!(
-                "{}h{}m{}s",
-                (self.time / 3600),
-                (self.time % 3600) / 60,
-                self.time % 60
-            )
-        };
+        f.write_str(&self.text(DurationFormat::Hms))
+    }
+}
+
+impl DataRow {
+    /// One line describing the entry, used by the text output
+    pub fn text(&self, durations: DurationFormat) -> String {
+        let time = durations.format(self.time.into());
 
         // Don't display nullable fields if they are null
-        write!(
-            f,
-            r#"{}{}{}{}{}Listening Time: {}, Date: {}, {}First Listened: {}, Last Listened: {}"#,
+        format!(
+            r#"Rank: {}, {}{}{}{}{}{}Listening Time: {}, {}{}Date: {}, {}{}First Listened: {}, Last Listened: {}"#,
+            self.rank,
             match &self.artist {
                 Some(k) => format!(r#"Artist: "{}", "#, k),
                 None => String::new(),
@@ -244,74 +1270,456 @@ impl Display for DataRow {
                 Some(k) => format!(r#"Genre: "{}", "#, k),
                 None => String::new(),
             },
+            match &self.device {
+                Some(k) => format!(r#"Device: "{}", "#, k),
+                None => String::new(),
+            },
             time,
+            match self.share {
+                Some(share) => format!("Share: {}, ", percentage(Some(share))),
+                None => String::new(),
+            },
+            match self.cumulative_share {
+                Some(share) => format!("Cumulative Share: {}, ", percentage(Some(share))),
+                None => String::new(),
+            },
             self.date,
             match &self.times_listened {
                 Some(k) => format!(r#"Times Listened: {}, "#, k),
                 None => String::new(),
             },
+            // Plays weren't counted by older versions
+            match (self.play_count, self.skip_rate) {
+                (0, _) => String::new(),
+                (plays, Some(rate)) => format!("Plays: {plays}, Skip Rate: {:.0}%, ", rate * 100.0),
+                (plays, None) => format!("Plays: {plays}, "),
+            },
             self.first_listened,
             self.last_listened
         )
     }
 }
 
-impl TimeGroup {
-    pub fn format_time(&self) -> String {
-        match self {
-            TimeGroup::Day => "%Y-%m-%d",
-            TimeGroup::Week => "%W",
-            TimeGroup::Month => "%m",
-            TimeGroup::Year => "%Y",
-            TimeGroup::AllTime => unreachable!(),
+/// Totals over everything that matches the filters of `print`, however it is grouped
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    /// Listening time in seconds
+    pub time: u64,
+    pub songs: u32,
+    pub artists: u32,
+    pub albums: u32,
+    /// Days with any listening
+    pub days: u32,
+    /// Listening time in seconds per day with any listening
+    pub average_per_day: u64,
+}
+
+impl Summary {
+    pub fn new(time: u64, songs: u32, artists: u32, albums: u32, days: u32) -> Self {
+        Summary {
+            time,
+            songs,
+            artists,
+            albums,
+            days,
+            average_per_day: time.checked_div(days.into()).unwrap_or_default(),
         }
-        .to_string()
+    }
+
+    /// One line footer for the text and table output
+    pub fn text(&self, durations: DurationFormat) -> String {
+        format!(
+            "Total Listening Time: {}, Songs: {}, Artists: {}, Albums: {}, Days Listened: {}, Average Per Day: {}",
+            durations.format(self.time),
+            self.songs,
+            self.artists,
+            self.albums,
+            self.days,
+            durations.format(self.average_per_day)
+        )
     }
 }
 
-impl Display for TimeGroup {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                TimeGroup::Day => "%Y-%m-%d",
-                TimeGroup::Week => "%Y-%W",
-                TimeGroup::Month => "%Y-%m",
-                TimeGroup::Year => "%Y",
-                TimeGroup::AllTime => unreachable!(),
-            }
+impl TimeGroup {
+    /// Whether the buckets repeat, like the days of the week, instead of following each other
+    pub fn is_cyclic(&self) -> bool {
+        matches!(
+            self,
+            TimeGroup::HourOfDay | TimeGroup::DayOfWeek | TimeGroup::MonthOfYear
         )
     }
+
+    /// Key of the time bucket following the one with `key`, in the same format the stats query
+    /// returns them in
+    pub fn next_key(&self, key: &str) -> Option<String> {
+        if self.is_cyclic() {
+            let position: u32 = key.parse().ok()?;
+            return Some(format!("{:0width$}", position + 1, width = key.len()));
+        }
+        let start: NaiveDate = key.parse().ok()?;
+        let next = match self {
+            TimeGroup::Day => start.checked_add_days(Days::new(1)),
+            TimeGroup::Week => start.checked_add_days(Days::new(7)),
+            TimeGroup::Month => start.checked_add_months(Months::new(1)),
+            TimeGroup::Quarter => start.checked_add_months(Months::new(3)),
+            TimeGroup::Year => start.checked_add_months(Months::new(12)),
+            TimeGroup::Decade => start.checked_add_months(Months::new(120)),
+            _ => None,
+        };
+        Some(next?.format("%Y-%m-%d").to_string())
+    }
+
+    /// Label of the time bucket with the key returned by the stats query, which is the first
+    /// day of the bucket or its position in the cycle. `long` labels spell out the dates of
+    /// weeks and the end of hours for people reading them, the short ones are plain keys like
+    /// 2024-W10 for machine readable output.
+    pub fn label(&self, key: &str, weeks: WeekStyle, long: bool) -> Option<String> {
+        if self.is_cyclic() {
+            let position: u32 = key.parse().ok()?;
+            return Some(match self {
+                TimeGroup::HourOfDay if long => {
+                    format!("{position:02}:00\u{2013}{:02}:00", position + 1)
+                }
+                TimeGroup::HourOfDay => format!("{position:02}:00"),
+                TimeGroup::DayOfWeek => {
+                    let mut day = weeks.first_day();
+                    for _ in 0..position {
+                        day = day.succ();
+                    }
+                    NaiveDate::from_isoywd_opt(2000, 1, day)?
+                        .format("%A")
+                        .to_string()
+                }
+                _ => NaiveDate::from_ymd_opt(2000, position, 1)?
+                    .format("%B")
+                    .to_string(),
+            });
+        }
+        let start: NaiveDate = key.parse().ok()?;
+        Some(match self {
+            TimeGroup::Day => start.format("%Y-%m-%d").to_string(),
+            TimeGroup::Week => {
+                let end = start + Days::new(6);
+                let (year, week) = match weeks {
+                    WeekStyle::Iso => (start.iso_week().year(), start.iso_week().week()),
+                    WeekStyle::Monday => (
+                        start.year(),
+                        start.format("%W").to_string().parse().unwrap_or_default(),
+                    ),
+                    WeekStyle::Sunday => (
+                        start.year(),
+                        start.format("%U").to_string().parse().unwrap_or_default(),
+                    ),
+                };
+                if !long {
+                    return Some(format!("{year}-W{week:02}"));
+                }
+                let range = if start.month() == end.month() {
+                    format!("{}\u{2013}{}", start.format("%b %-d"), end.day())
+                } else {
+                    format!("{}\u{2013}{}", start.format("%b %-d"), end.format("%b %-d"))
+                };
+                format!("{year}-W{week:02} ({range})")
+            }
+            TimeGroup::Month => start.format("%Y-%m").to_string(),
+            TimeGroup::Quarter => format!("{}-Q{}", start.year(), start.month0() / 3 + 1),
+            TimeGroup::Year => start.format("%Y").to_string(),
+            TimeGroup::Decade => format!("{}s", start.year()),
+            _ => unreachable!(),
+        })
+    }
 }
 
-impl Default for TimeGroup {
-    fn default() -> Self {
-        Self::AllTime
+impl WeekStyle {
+    pub fn first_day(&self) -> Weekday {
+        match self {
+            WeekStyle::Iso | WeekStyle::Monday => Weekday::Mon,
+            WeekStyle::Sunday => Weekday::Sun,
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct NewlineFormatter;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    fn filters(args: &[&str]) -> Filters {
+        PrintArgs::try_parse_from(["print"].iter().chain(args))
+            .unwrap()
+            .filters
+    }
+
+    #[test]
+    fn parses_spans() {
+        let before = |span: &str, day: &str| {
+            span.parse::<Span>()
+                .map(|x| x.before(date(day)).unwrap().to_string())
+        };
+        assert_eq!(before("7d", "2024-03-10").unwrap(), "2024-03-03");
+        assert_eq!(before("2 weeks", "2024-03-10").unwrap(), "2024-02-25");
+        assert_eq!(before("3months", "2024-05-31").unwrap(), "2024-02-29");
+        assert_eq!(before(" 1Y ", "2024-02-29").unwrap(), "2023-02-28");
+        assert!(before("0d", "2024-03-10").is_err());
+        assert!(before("d", "2024-03-10").is_err());
+        assert!(before("5 parsecs", "2024-03-10").is_err());
+    }
 
-impl Formatter for NewlineFormatter {
-    #[inline]
-    fn begin_array_value<W>(&mut self, writer: &mut W, first: bool) -> io::Result<()>
-    where
-        W: ?Sized + io::Write,
-    {
-        if first {
-            writer.write_all(b"\n")
-        } else {
-            writer.write_all(b",\n")
+    #[test]
+    fn parses_relative_dates() {
+        let resolve = |when: &str| {
+            when.parse::<RelativeDate>()
+                .map(|x| x.resolve(date("2024-03-10")).unwrap().to_string())
+        };
+        assert_eq!(resolve("today").unwrap(), "2024-03-10");
+        assert_eq!(resolve("Yesterday").unwrap(), "2024-03-09");
+        assert_eq!(resolve("2 weeks ago").unwrap(), "2024-02-25");
+        assert_eq!(resolve("1month ago").unwrap(), "2024-02-10");
+        assert_eq!(resolve("2023-12-24").unwrap(), "2023-12-24");
+        assert!(resolve("next week").is_err());
+        assert!(resolve("ago").is_err());
+    }
+
+    #[test]
+    fn finds_period_ranges() {
+        // A Wednesday
+        let today = date("2024-03-13");
+        for (period, week_start, start, end) in [
+            (Period::Today, Weekday::Mon, "2024-03-13", "2024-03-13"),
+            (Period::Yesterday, Weekday::Mon, "2024-03-12", "2024-03-12"),
+            (Period::ThisWeek, Weekday::Mon, "2024-03-11", "2024-03-17"),
+            (Period::ThisWeek, Weekday::Sun, "2024-03-10", "2024-03-16"),
+            (Period::LastWeek, Weekday::Mon, "2024-03-04", "2024-03-10"),
+            (Period::ThisMonth, Weekday::Mon, "2024-03-01", "2024-03-31"),
+            (Period::LastMonth, Weekday::Mon, "2024-02-01", "2024-02-29"),
+            (Period::ThisYear, Weekday::Mon, "2024-01-01", "2024-12-31"),
+            (Period::LastYear, Weekday::Mon, "2023-01-01", "2023-12-31"),
+            (Period::Ytd, Weekday::Mon, "2024-01-01", "2024-03-13"),
+        ] {
+            assert_eq!(
+                period.range(today, week_start),
+                (date(start), date(end)),
+                "{period:?} {week_start}"
+            );
         }
+        assert_eq!(
+            Period::LastMonth.range(date("2024-01-15"), Weekday::Mon),
+            (date("2023-12-01"), date("2023-12-31"))
+        );
+        assert_eq!(
+            Period::ThisWeek.range(date("2024-03-10"), Weekday::Sun),
+            (date("2024-03-10"), date("2024-03-16"))
+        );
+    }
+
+    #[test]
+    fn resolves_relative_ranges() {
+        let today = date("2024-03-13");
+        let bounds = |args: &[&str]| {
+            let mut filters = filters(args);
+            filters.resolve_dates(today, Weekday::Mon).unwrap();
+            filters.date_bounds()
+        };
+        assert_eq!(
+            bounds(&["--last", "7d"]),
+            (Some(date("2024-03-07")), Some(today))
+        );
+        assert_eq!(
+            bounds(&["--since", "yesterday"]),
+            (Some(date("2024-03-12")), Some(today))
+        );
+        assert_eq!(
+            bounds(&["--period", "last-week"]),
+            (Some(date("2024-03-04")), Some(date("2024-03-10")))
+        );
+        assert_eq!(bounds(&[]), (None, None));
+    }
+
+    #[test]
+    fn parses_date_bounds() {
+        let bound = |s: &str| s.parse::<DateBound>().map(|x| (x.date, x.datetime()));
+        let time = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").ok();
+        assert_eq!(bound("2024-03-10").unwrap(), (date("2024-03-10"), None));
+        for s in [
+            "2024-03-10 18:30",
+            "2024-03-10T18:30",
+            "2024-03-10 18:30:00",
+        ] {
+            assert_eq!(
+                bound(s).unwrap(),
+                (date("2024-03-10"), time("2024-03-10 18:30:00")),
+                "{s}"
+            );
+        }
+        assert_eq!(
+            bound("2024-03-10T18:30:15").unwrap().1,
+            time("2024-03-10 18:30:15")
+        );
+        assert!(bound("2024-03-10 25:00").is_err());
+        assert!(bound("2024-03-10 18").is_err());
+        assert!(bound("yesterday").is_err());
+    }
+
+    #[test]
+    fn narrows_date_bounds() {
+        let bounds = |args: &[&str]| filters(args).date_bounds();
+        let days = |start, end| (Some(date(start)), Some(date(end)));
+        assert_eq!(
+            bounds(&["--between", "2024-03-01", "2024-03-31"]),
+            days("2024-03-01", "2024-03-31")
+        );
+        assert_eq!(
+            bounds(&["--between", "2024-03-01", "2024-03-31", "--exclusive"]),
+            days("2024-03-02", "2024-03-30")
+        );
+        assert_eq!(
+            bounds(&["--after", "2024-03-05", "--before", "2024-03-09"]),
+            days("2024-03-05", "2024-03-09")
+        );
+        assert_eq!(
+            bounds(&[
+                "--after",
+                "2024-03-05",
+                "--before",
+                "2024-03-09",
+                "--exclusive"
+            ]),
+            days("2024-03-06", "2024-03-08")
+        );
+        assert_eq!(
+            bounds(&[
+                "--between",
+                "2024-03-01",
+                "2024-03-31",
+                "--after",
+                "2024-03-05"
+            ]),
+            days("2024-03-05", "2024-03-31")
+        );
+    }
+
+    #[test]
+    fn keeps_the_dates_of_timed_bounds() {
+        let timed = filters(&[
+            "--after",
+            "2024-03-05 18:00",
+            "--before",
+            "2024-03-09T08:00",
+            "--exclusive",
+        ]);
+        assert_eq!(
+            timed.date_bounds(),
+            (Some(date("2024-03-05")), Some(date("2024-03-09")))
+        );
+        assert!(timed.has_times());
+        assert_eq!(
+            timed.time_bounds(),
+            (
+                Some(date("2024-03-05").and_hms_opt(18, 0, 0).unwrap()),
+                Some(date("2024-03-09").and_hms_opt(8, 0, 0).unwrap())
+            )
+        );
+        assert!(!filters(&["--after", "2024-03-05"]).has_times());
+    }
+
+    #[test]
+    fn finds_heatmap_ranges() {
+        let range = |args: &[&str]| {
+            HeatmapArgs::try_parse_from(["heatmap"].iter().chain(args))
+                .unwrap()
+                .range(date("2024-03-13"))
+        };
+        assert_eq!(
+            range(&[]).unwrap(),
+            (date("2023-03-14"), date("2024-03-13"))
+        );
+        assert_eq!(
+            range(&["-y", "2023"]).unwrap(),
+            (date("2023-01-01"), date("2023-12-31"))
+        );
+        // The weeks padding the calendar don't exist
+        assert!(range(&["-y", "262143"]).is_err());
+        assert!(range(&["-y", "262144"]).is_err());
+    }
+
+    #[test]
+    fn labels_weeks() {
+        let label = |key, weeks, long| TimeGroup::Week.label(key, weeks, long).unwrap();
+        assert_eq!(label("2024-12-30", WeekStyle::Iso, false), "2025-W01");
+        assert_eq!(
+            label("2024-12-30", WeekStyle::Iso, true),
+            "2025-W01 (Dec 30\u{2013}Jan 5)"
+        );
+        assert_eq!(label("2024-12-30", WeekStyle::Monday, false), "2024-W53");
+        assert_eq!(label("2024-03-10", WeekStyle::Sunday, false), "2024-W10");
+        assert_eq!(
+            label("2024-03-10", WeekStyle::Sunday, true),
+            "2024-W10 (Mar 10\u{2013}16)"
+        );
+    }
+
+    #[test]
+    fn labels_time_groups() {
+        let label = |group: TimeGroup, key, long| group.label(key, WeekStyle::Iso, long).unwrap();
+        assert_eq!(label(TimeGroup::Day, "2024-03-10", true), "2024-03-10");
+        assert_eq!(label(TimeGroup::Month, "2024-03-01", true), "2024-03");
+        assert_eq!(label(TimeGroup::Quarter, "2024-07-01", true), "2024-Q3");
+        assert_eq!(label(TimeGroup::Year, "2024-01-01", true), "2024");
+        assert_eq!(label(TimeGroup::Decade, "2020-01-01", true), "2020s");
+        assert_eq!(label(TimeGroup::HourOfDay, "07", false), "07:00");
+        assert_eq!(
+            label(TimeGroup::HourOfDay, "23", true),
+            "23:00\u{2013}24:00"
+        );
+        assert_eq!(label(TimeGroup::DayOfWeek, "0", true), "Monday");
+        assert_eq!(
+            TimeGroup::DayOfWeek
+                .label("0", WeekStyle::Sunday, true)
+                .unwrap(),
+            "Sunday"
+        );
+        assert_eq!(label(TimeGroup::MonthOfYear, "03", true), "March");
+        assert_eq!(TimeGroup::Week.label("W10", WeekStyle::Iso, true), None);
+    }
+
+    #[test]
+    fn finds_next_keys() {
+        let next = |group: TimeGroup, key| group.next_key(key);
+        assert_eq!(
+            next(TimeGroup::Day, "2024-02-28").as_deref(),
+            Some("2024-02-29")
+        );
+        assert_eq!(
+            next(TimeGroup::Week, "2024-12-30").as_deref(),
+            Some("2025-01-06")
+        );
+        assert_eq!(
+            next(TimeGroup::Month, "2024-01-31").as_deref(),
+            Some("2024-02-29")
+        );
+        assert_eq!(
+            next(TimeGroup::Quarter, "2024-10-01").as_deref(),
+            Some("2025-01-01")
+        );
+        assert_eq!(
+            next(TimeGroup::Decade, "2020-01-01").as_deref(),
+            Some("2030-01-01")
+        );
+        assert_eq!(next(TimeGroup::HourOfDay, "07").as_deref(), Some("08"));
+        assert_eq!(next(TimeGroup::AllTime, "2024-01-01"), None);
+        assert_eq!(next(TimeGroup::Day, "today"), None);
     }
 
-    #[inline]
-    fn end_array<W>(&mut self, writer: &mut W) -> io::Result<()>
-    where
-        W: ?Sized + io::Write,
-    {
-        writer.write_all(b"\n]")
+    #[test]
+    fn rejects_patterns_that_dont_compile() {
+        assert_eq!(parse_pattern("Muse").unwrap(), "Muse");
+        assert_eq!(parse_pattern("radio*").unwrap(), "radio*");
+        assert_eq!(parse_pattern("/^The .*s$/").unwrap(), "/^The .*s$/");
+        assert!(parse_pattern("/(/").is_err());
+        assert!(parse_pattern("foo[").is_err());
+        assert!(parse_pattern("[z-a]*").is_err());
     }
 }
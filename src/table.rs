@@ -0,0 +1,146 @@
+//! Aligned table output for `print`
+
+use std::io::{self, Write};
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::structs::{DataRow, DurationFormat};
+
+/// Names are never truncated below this width, or their header if it's longer
+const MIN_WIDTH: usize = 8;
+const SEPARATOR: &str = "  ";
+
+/// Write `rows` as a table with a header, shrinking the text columns until the table fits into
+/// `max_width` if given
+pub fn write_table(
+    out: &mut impl Write,
+    rows: &[DataRow],
+    mut columns: &[&str],
+    max_width: Option<usize>,
+    color: bool,
+    durations: DurationFormat,
+) -> io::Result<()> {
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|x| row.display_value(x, durations))
+                .collect()
+        })
+        .collect();
+    let mut widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            cells
+                .iter()
+                .map(|x| x[i].width())
+                .chain([DataRow::header(column).width()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    if let Some(max_width) = max_width {
+        let min_widths: Vec<usize> = columns
+            .iter()
+            .zip(&widths)
+            .map(|(column, width)| match is_shrinkable(column) {
+                true => *width.min(&MIN_WIDTH.max(DataRow::header(column).width())),
+                false => *width,
+            })
+            .collect();
+        // Leave out the columns at the end if the table can't fit otherwise
+        let mut kept = columns.len();
+        while kept > 1 && table_width(&min_widths[..kept]) > max_width {
+            kept -= 1;
+        }
+        columns = &columns[..kept];
+        widths.truncate(kept);
+
+        // Take one character at a time from the widest column so that long titles give up
+        // more room than short genres
+        let mut total = table_width(&widths);
+        while total > max_width {
+            let Some(widest) = (0..kept)
+                .filter(|&i| widths[i] > min_widths[i])
+                .max_by_key(|&i| widths[i])
+            else {
+                break;
+            };
+            widths[widest] -= 1;
+            total -= 1;
+        }
+    }
+
+    let header: Vec<String> = columns
+        .iter()
+        .map(|x| DataRow::header(x).to_owned())
+        .collect();
+    write_row(out, &header, columns, &widths, color.then_some("\x1b[1m"))?;
+    for row in &cells {
+        write_row(out, row, columns, &widths, None)?;
+    }
+    Ok(())
+}
+
+/// Only names can be cut short without making the value useless
+fn is_shrinkable(column: &str) -> bool {
+    matches!(column, "title" | "artist" | "album" | "genre" | "device")
+}
+
+fn table_width(widths: &[usize]) -> usize {
+    widths.iter().sum::<usize>() + SEPARATOR.len() * widths.len().saturating_sub(1)
+}
+
+fn write_row(
+    out: &mut impl Write,
+    cells: &[String],
+    columns: &[&str],
+    widths: &[usize],
+    style: Option<&str>,
+) -> io::Result<()> {
+    let mut line = String::new();
+    // Cells of left out columns are skipped
+    for (i, cell) in cells.iter().take(widths.len()).enumerate() {
+        if i > 0 {
+            line.push_str(SEPARATOR);
+        }
+        let cell = truncate(cell, widths[i]);
+        let padding = " ".repeat(widths[i] - cell.width());
+        // The last column doesn't need trailing spaces
+        if DataRow::is_numeric(columns[i]) {
+            line.push_str(&padding);
+            line.push_str(&cell);
+        } else if i + 1 < widths.len() {
+            line.push_str(&cell);
+            line.push_str(&padding);
+        } else {
+            line.push_str(&cell);
+        }
+    }
+    match style {
+        Some(style) => writeln!(out, "{style}{line}\x1b[0m"),
+        None => writeln!(out, "{line}"),
+    }
+}
+
+/// Cut `cell` down to `width` columns, marking the cut with an ellipsis
+pub(crate) fn truncate(cell: &str, width: usize) -> String {
+    if cell.width() <= width {
+        return cell.to_owned();
+    }
+    let mut truncated = String::new();
+    let mut used = 0;
+    for c in cell.chars() {
+        let w = c.width().unwrap_or_default();
+        if used + w + 1 > width {
+            break;
+        }
+        truncated.push(c);
+        used += w;
+    }
+    truncated.push('…');
+    truncated
+}
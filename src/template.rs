@@ -0,0 +1,294 @@
+//! `print --template` line formats
+
+use std::{fmt::Write, str::FromStr};
+
+use anyhow::{anyhow, bail, Error, Result};
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    structs::{parse_column, DataRow, DurationFormat},
+    table::truncate,
+};
+
+/// Columns holding a number of seconds
+const DURATIONS: &[&str] = &["time", "duration"];
+/// Columns holding a Y-M-D date
+const DATES: &[&str] = &["date", "first_listened", "last_listened"];
+
+/// A line with `{column}` or `{column:formatter:...}` fields that are filled in for every entry.
+/// Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone)]
+pub struct Template(Vec<Part>);
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Field {
+        column: &'static str,
+        formatters: Vec<Formatter>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Formatter {
+    /// Same as the --duration-format options
+    Duration(DurationFormat),
+    /// 1:02:03
+    Clock,
+    /// Whole minutes
+    Minutes,
+    /// strftime format such as %b %d
+    Date(String),
+    /// Pad to a width, aligned left, right or centered
+    Pad(Align, usize),
+    /// Cut down to a width with an ellipsis
+    Truncate(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+impl Template {
+    /// Fill in the fields with the values of `row`, writing times in `durations` unless the
+    /// field says otherwise
+    pub fn render(&self, row: &DataRow, durations: DurationFormat) -> String {
+        let mut line = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => line.push_str(text),
+                Part::Field { column, formatters } => {
+                    let mut value = row.display_value(column, durations);
+                    for formatter in formatters {
+                        value = formatter.apply(&value, &row.value(column));
+                    }
+                    line.push_str(&value);
+                }
+            }
+        }
+        line
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '}' => bail!("Unmatched '}}' in template, write '}}}}' for a literal brace"),
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| anyhow!("Unclosed '{{' in template"))?;
+                    chars = rest[end + 1..].chars();
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(parse_field(&rest[..end])?);
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template(parts))
+    }
+}
+
+/// Parse the inside of a `{...}` field
+fn parse_field(field: &str) -> Result<Part> {
+    let (column, mut spec) = field.split_once(':').unwrap_or((field, ""));
+    let column = parse_column(column).map_err(|e| anyhow!("In template field {{{field}}}: {e}"))?;
+    let mut formatters = Vec::new();
+    while !spec.is_empty() {
+        // Date formats can contain colons themselves so they always come last
+        let formatter = if spec.starts_with('%') {
+            std::mem::take(&mut spec)
+        } else {
+            let (formatter, rest) = spec.split_once(':').unwrap_or((spec, ""));
+            spec = rest;
+            formatter
+        };
+        formatters.push(
+            Formatter::parse(column, formatter)
+                .map_err(|e| anyhow!("In template field {{{field}}}: {e}"))?,
+        );
+    }
+    Ok(Part::Field { column, formatters })
+}
+
+impl Formatter {
+    fn parse(column: &str, formatter: &str) -> Result<Self> {
+        let duration = |formatter| match DURATIONS.contains(&column) {
+            true => Ok(formatter),
+            false => Err(anyhow!(
+                "durations can only be formatted for {}",
+                DURATIONS.join(", ")
+            )),
+        };
+        let width = |width: &str| {
+            width
+                .parse()
+                .map_err(|_| anyhow!("unknown formatter '{formatter}'"))
+        };
+        if let Ok(format) = DurationFormat::from_str(formatter, false) {
+            return duration(Formatter::Duration(format));
+        }
+        match formatter {
+            "clock" => duration(Formatter::Clock),
+            "minutes" => duration(Formatter::Minutes),
+            date if date.starts_with('%') => {
+                if !DATES.contains(&column) {
+                    bail!("dates can only be formatted for {}", DATES.join(", "));
+                }
+                // Formatting fails on unknown specifiers and ones that need a time of day
+                if write!(String::new(), "{}", NaiveDate::MIN.format(date)).is_err() {
+                    bail!("invalid date format '{date}'");
+                }
+                Ok(Formatter::Date(date.to_owned()))
+            }
+            _ if formatter.starts_with('<') => {
+                Ok(Formatter::Pad(Align::Left, width(&formatter[1..])?))
+            }
+            _ if formatter.starts_with('>') => {
+                Ok(Formatter::Pad(Align::Right, width(&formatter[1..])?))
+            }
+            _ if formatter.starts_with('^') => {
+                Ok(Formatter::Pad(Align::Center, width(&formatter[1..])?))
+            }
+            _ if formatter.starts_with('.') => Ok(Formatter::Truncate(width(&formatter[1..])?)),
+            _ => Ok(Formatter::Pad(Align::Left, width(formatter)?)),
+        }
+    }
+
+    /// Format `value`, the field formatted so far. Durations and dates are formatted from the
+    /// `raw` value of the column instead, and are left alone if it is missing.
+    fn apply(&self, value: &str, raw: &str) -> String {
+        let seconds = || raw.parse::<u64>().ok();
+        let formatted = match self {
+            Formatter::Duration(format) => seconds().map(|x| format.format(x)),
+            Formatter::Clock => {
+                seconds().map(|x| format!("{}:{:02}:{:02}", x / 3600, (x % 3600) / 60, x % 60))
+            }
+            Formatter::Minutes => seconds().map(|x| (x / 60).to_string()),
+            // Periods like weeks are labelled instead of being plain dates
+            Formatter::Date(format) => NaiveDate::from_str(raw)
+                .ok()
+                .map(|x| x.format(format).to_string()),
+            Formatter::Pad(align, width) => {
+                let padding = width.saturating_sub(value.width());
+                Some(match align {
+                    Align::Left => format!("{value}{}", " ".repeat(padding)),
+                    Align::Right => format!("{}{value}", " ".repeat(padding)),
+                    Align::Center => format!(
+                        "{}{value}{}",
+                        " ".repeat(padding / 2),
+                        " ".repeat(padding - padding / 2)
+                    ),
+                })
+            }
+            Formatter::Truncate(width) => Some(truncate(value, *width)),
+        };
+        formatted.unwrap_or_else(|| value.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row() -> DataRow {
+        DataRow {
+            artist_id: Some(1),
+            song_id: Some(2),
+            title: Some("Paranoid Android".to_owned()),
+            artist: Some("Radiohead".to_owned()),
+            album: Some("OK Computer".to_owned()),
+            genre: None,
+            time: 3725,
+            duration: Some(387),
+            first_listened: "2024-03-01".parse().unwrap(),
+            last_listened: "2024-03-10".parse().unwrap(),
+            times_listened: Some(9),
+            device: None,
+            date: "2024-W10".to_owned(),
+            play_count: 12,
+            skip_rate: None,
+            share: None,
+            cumulative_share: None,
+            rank: 1,
+        }
+    }
+
+    fn render(template: &str) -> String {
+        template
+            .parse::<Template>()
+            .unwrap()
+            .render(&row(), DurationFormat::Hms)
+    }
+
+    fn error(template: &str) -> String {
+        template.parse::<Template>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn fills_in_fields() {
+        assert_eq!(
+            render("{rank}. {artist} - {title} ({time})"),
+            "1. Radiohead - Paranoid Android (1h2m5s)"
+        );
+        assert_eq!(render("{{{artist}}} }}{{"), "{Radiohead} }{");
+        assert_eq!(render("[{genre}]"), "[]");
+        assert_eq!(render(""), "");
+    }
+
+    #[test]
+    fn applies_formatters() {
+        assert_eq!(render("{artist:<12}|"), "Radiohead   |");
+        assert_eq!(render("{artist:12}|"), "Radiohead   |");
+        assert_eq!(render("{artist:>12}|"), "   Radiohead|");
+        assert_eq!(render("{artist:^12}|"), " Radiohead  |");
+        assert_eq!(render("{artist:.6}"), "Radio…");
+        assert_eq!(render("{artist:4}"), "Radiohead");
+        assert_eq!(render("{time:seconds}"), "3725");
+        assert_eq!(render("{time:clock}"), "1:02:05");
+        assert_eq!(render("{duration:minutes}"), "6");
+        assert_eq!(render("{time:clock:>9}"), "  1:02:05");
+        assert_eq!(render("{first_listened:%b %d}"), "Mar 01");
+        assert_eq!(render("{first_listened:%d:%m}"), "01:03");
+        // Periods that aren't plain dates keep their label
+        assert_eq!(render("{date:%Y}"), "2024-W10");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(error("{artist").contains("Unclosed"));
+        assert!(error("artist}").contains("Unmatched"));
+        assert!(error("{colour}").contains("{colour}"));
+        assert!(error("{artist:hms}").contains("durations can only be formatted"));
+        assert!(error("{time:%Y}").contains("dates can only be formatted"));
+        assert!(error("{date:%H:%M}").contains("invalid date format"));
+        assert!(error("{date:%Q}").contains("invalid date format"));
+        assert!(error("{artist:<x}").contains("unknown formatter '<x'"));
+        assert!(error("{artist:bold}").contains("unknown formatter 'bold'"));
+    }
+}
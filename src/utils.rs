@@ -2,21 +2,34 @@ use std::{
     cmp::Ordering,
     collections::HashMap,
     fs::File,
+    io::{BufWriter, IsTerminal, Write},
     path::PathBuf,
+    str::FromStr,
     time::Duration,
 };
 
-use crate::structs::{Config, DataRow, FieldGroup, NewlineFormatter, PrintArgs, TimeGroup};
+use crate::{
+    chart::{write_bars, write_sparklines, Charset},
+    compare::write_comparison,
+    heatmap::write_heatmap,
+    storage::{NewSong, Storage},
+    structs::{
+        ChartStyle, ColorChoice, CompareArgs, Config, DataRow, DurationFormat, FieldGroup,
+        HeatmapArgs, OutputFormat, PrintArgs, SortBy, SortKey, TimeGroup,
+    },
+    table::write_table,
+    template::Template,
+};
 use anyhow::{anyhow, Result};
+use chrono::Weekday;
+use clap::ValueEnum;
 use fs2::FileExt;
 use log::{info, warn};
 use mpd::{Client, State};
-use serde::Serialize;
-use serde_json::Serializer;
-use sqlx::{QueryBuilder, Sqlite};
+use terminal_size::{terminal_size, Width};
 use tokio::time::Instant;
 
-pub async fn run(pool: &sqlx::SqlitePool, config: &Config) -> Result<()> {
+pub async fn run<S: Storage>(storage: &S, config: &Config) -> Result<()> {
     // Use file locks to prevent multiple instances running at once since data will be written
     // twice to the database (probably not what you want if you're looking for accurate statistics)
     let lock_file =
@@ -54,23 +67,12 @@ pub async fn run(pool: &sqlx::SqlitePool, config: &Config) -> Result<()> {
                     .and_then(|x| x.find(" - ").map(|ind| x[..ind].trim().to_string()))
             }
         };
+        let artist = artist.ok_or(anyhow!("No artist found for '{}'", outer_song.file))?;
         let duration = outer_song.duration.map(|x| x.as_secs() as u32);
-        let artist_id = match sqlx::query!("SELECT * FROM artists WHERE name = $1", artist)
-            .fetch_optional(pool)
-            .await?
-        {
-            Some(k) => k.id,
-            None => {
-                sqlx::query!("INSERT INTO artists VALUES ($1, $2)", None::<u8>, artist)
-                    .execute(pool)
-                    .await?;
-                info!(
-                    "Inserting new artist into database: '{}'",
-                    artist.unwrap_or_default()
-                );
-                continue;
-            }
-        };
+        let artist_id = storage.upsert_artist(&artist).await?;
+        if artist_id.inserted {
+            info!("Inserting new artist into database: '{}'", artist);
+        }
         let title: Option<Box<str>> = match &outer_song.title {
             Some(k) => Some(k[..].into()),
             None => {
@@ -87,61 +89,29 @@ pub async fn run(pool: &sqlx::SqlitePool, config: &Config) -> Result<()> {
             }
         };
 
+        let title = title.ok_or(anyhow!("No title found for '{}'", outer_song.file))?;
+        let song_id = storage
+            .upsert_song(&NewSong {
+                title: &title,
+                artist_id: artist_id.id,
+                album: tags.get("Album").map(|x| &x[..]),
+                genre: tags.get("Genre").map(|x| &x[..]),
+                duration,
+            })
+            .await?;
+        if song_id.inserted {
+            info!("Inserting new song into database: '{}'", title);
+        }
 
-        let song_id = match sqlx::query!("SELECT id, title FROM songs WHERE title = $1", title)
-            .fetch_optional(pool)
-            .await?
-        {
-            Some(k) => k.id,
-            None => {
-                let album = tags.get("Album");
-                let genre = tags.get("Genre");
-                sqlx::query!(
-                    "INSERT INTO songs VALUES ($1, $2, $3, $4, $5, $6)",
-                    None::<u8>,
-                    title,
-                    artist_id,
-                    album,
-                    genre,
-                    duration
-                )
-                .execute(pool)
-                .await?;
-                info!(
-                    "Inserting new song into database: '{}'",
-                    title.unwrap_or_default()
-                );
-                continue;
-            }
-        };
-
-        info!(
-            "Tracking stats for: '{} - {}'",
-            artist.as_deref().unwrap_or_default(),
-            title.as_deref().unwrap_or_default()
-        );
+        info!("Tracking stats for: '{} - {}'", artist, title);
 
-        let date = chrono::Local::now().date_naive();
-        if sqlx::query!(
-            "SELECT * from listening_times where date = $1 and song_id = $2",
-            date,
-            song_id
-        )
-        .fetch_optional(pool)
-        .await?
-        .is_none()
-        {
-            sqlx::query!(
-                "INSERT INTO listening_times VALUES ($1, $2, $3, $4)",
-                None::<u8>,
-                date,
-                song_id,
-                0
+        let play = storage
+            .start_play(
+                chrono::Local::now().naive_local(),
+                song_id.id,
+                &config.device_id,
             )
-            .execute(pool)
             .await?;
-        }
-
         let mut current_time = mpd
             .status()?
             .time
@@ -168,9 +138,7 @@ pub async fn run(pool: &sqlx::SqlitePool, config: &Config) -> Result<()> {
                             .0;
                         match old_time.cmp(&current_time) {
                             Ordering::Less => {
-                                sqlx::query!("UPDATE listening_times SET playback_time = playback_time + 1 WHERE song_id = $1 AND date = $2", song_id, date)
-                                    .execute(pool)
-                                    .await?;
+                                storage.add_playback_time(&play, 1).await?;
                                 old_time = current_time
                             }
                             Ordering::Greater => old_time = current_time,
@@ -187,6 +155,12 @@ pub async fn run(pool: &sqlx::SqlitePool, config: &Config) -> Result<()> {
                         )
                         .await
                     } else {
+                        // Count songs that were switched away from before reaching half of
+                        // their duration as skipped
+                        if duration.is_some_and(|x| current_time.as_secs() < u64::from(x) / 2) {
+                            info!("Skipped: '{} - {}'", artist, title);
+                            storage.skip_play(&play).await?;
+                        }
                         continue 'outer;
                     }
                 }
@@ -195,154 +169,329 @@ pub async fn run(pool: &sqlx::SqlitePool, config: &Config) -> Result<()> {
     }
 }
 
-pub async fn print(pool: &sqlx::SqlitePool, command: PrintArgs) -> Result<()> {
-    // Convert the Vec of enums into comma separated strings to feed them into the sql query
-    let sort_sequence = command
-        .sort
-        .iter()
-        .map(|x| x.to_string())
-        .reduce(|acc, x| acc + "," + &x)
-        .unwrap_or_default();
-    let mut builder: QueryBuilder<Sqlite> = sqlx::QueryBuilder::new(
-        "
-SELECT
-    songs.title as title,
-    songs.album as album,
-    songs.genre as genre,
-    songs.id as song_id,
-    songs.duration as duration,
-    artists.name as artist,
-    artists.id as artist_id,
-    MIN(listening_times.date) AS first_listened,
-    MAX(listening_times.date) AS last_listened,
-    SUM(listening_times.playback_time) as time,
-    SUM(listening_times.playback_time) / songs.duration as times_listened,
-",
-    );
-    match command
-        .time_group
-        .as_ref()
-        .expect("Default value set by clap")
-    {
-        TimeGroup::AllTime => builder.push(
-            "
-listening_times.date as date
-FROM songs
-INNER JOIN listening_times
-ON songs.id = listening_times.song_id 
-INNER JOIN artists 
-ON artists.id = songs.artist_id
-",
-        ),
-        group => builder.push(format!(
-            "
-strftime('{group}', listening_times.date) AS date
-FROM songs
-INNER JOIN listening_times
-ON songs.id = listening_times.song_id 
-INNER JOIN artists 
-ON artists.id = songs.artist_id
-"
-        )),
+pub async fn print<S: Storage>(storage: &S, command: PrintArgs) -> Result<()> {
+    // Parse the template first so that mistakes don't have to wait for the query
+    let template = command
+        .template
+        .as_deref()
+        .map(Template::from_str)
+        .transpose()?;
+    let is_terminal = std::io::stdout().is_terminal();
+    let format = match (command.json, command.format) {
+        (true, _) => OutputFormat::Json,
+        (_, Some(format)) => format,
+        (_, None) if is_terminal => OutputFormat::Table,
+        (_, None) => OutputFormat::Text,
     };
+    if command.summary && matches!(format, OutputFormat::Csv | OutputFormat::Tsv) {
+        return Err(anyhow!("--summary doesn't work with CSV and TSV output"));
+    }
+    let durations = command.duration_format.unwrap_or_default();
+    let summary = match command.summary {
+        true => Some(storage.summary(&command.filters).await?),
+        false => None,
+    };
+    // Everything but tables is written out row by row while it's read from the database
+    let mut out = BufWriter::new(std::io::stdout());
 
-    // Use one match statement to determine which where clause to use since only one can be used at
-    // a time
-    builder.push(match (command.after, command.before, command.between) {
-        (Some(after), _, _) => format!("WHERE date > '{}' ", after),
-        (_, Some(before), _) => format!("WHERE date < '{}' ", before),
-        (_, _, Some(between)) => {
-            format!("WHERE date BETWEEN '{}' and '{}' ", between[0], between[1])
+    if let Some(style) = command.chart {
+        print_chart(storage, &command, style, durations, &mut out).await?;
+        if let Some(summary) = summary {
+            writeln!(out, "\n{}", summary.text(durations))?;
         }
-        (None, None, None) => String::new(),
-    });
-
-    builder.push(format!(
-        "GROUP BY {} ",
-        command
-            .field_group
-            .as_ref()
-            .expect("Default value set by clap")
-    ));
+        out.flush()?;
+        return Ok(());
+    }
 
-    match command
-        .time_group
-        .as_ref()
-        .expect("Default value set by clap")
-    {
-        TimeGroup::AllTime => (),
-        group => {
-            builder.push(format!(", strftime('{}', date)", group.format_time()));
+    if let Some(template) = template {
+        storage
+            .for_each_stat(&command, |mut row| {
+                label_period(&mut row, &command, false);
+                Ok(writeln!(out, "{}", template.render(&row, durations))?)
+            })
+            .await?;
+        if let Some(summary) = summary {
+            writeln!(out, "\n{}", summary.text(durations))?;
         }
+        out.flush()?;
+        return Ok(());
     }
 
-    builder.push(format!("ORDER BY {sort_sequence}"));
-
-    // Fetch each entry from the database using the provided query and print to stdout
-    let mut query = sqlx::query_as::<_, DataRow>(builder.sql())
-        .fetch_all(pool)
-        .await?;
-
-    // Hide fields that don't make sense for specific groupings
-    // Had to resort to this since the alternative would be to make 4 separate structs
-    // with slightly different fields
-    match command
-        .field_group
-        .as_ref()
-        .expect("Default value set by clap")
-    {
-        FieldGroup::Album => {
-            for entry in query.iter_mut() {
-                entry.duration = None;
-                entry.title = None;
-                entry.song_id = None;
-                entry.times_listened = None;
+    match format {
+        OutputFormat::Json => {
+            // One entry per line so that the output is easy to skim and diff, with the rows
+            // wrapped in an object next to the summary if there is one
+            if summary.is_some() {
+                write!(out, "{{\"rows\":")?;
+            }
+            write!(out, "[")?;
+            let mut first = true;
+            storage
+                .for_each_stat(&command, |mut row| {
+                    label_period(&mut row, &command, false);
+                    out.write_all(if first { b"\n" } else { b",\n" })?;
+                    first = false;
+                    Ok(serde_json::to_writer(&mut out, &row)?)
+                })
+                .await?;
+            write!(out, "\n]")?;
+            if let Some(summary) = summary {
+                write!(out, ",\n\"summary\":")?;
+                serde_json::to_writer(&mut out, &summary)?;
+                write!(out, "}}")?;
             }
+            writeln!(out)?;
         }
-        FieldGroup::Artist => {
-            for entry in query.iter_mut() {
-                entry.duration = None;
-                entry.title = None;
-                entry.song_id = None;
-                entry.album = None;
-                entry.genre = None;
-                entry.times_listened = None;
+        OutputFormat::Ndjson => {
+            storage
+                .for_each_stat(&command, |mut row| {
+                    label_period(&mut row, &command, false);
+                    serde_json::to_writer(&mut out, &row)?;
+                    Ok(writeln!(out)?)
+                })
+                .await?;
+            if let Some(summary) = summary {
+                write!(out, "{{\"summary\":")?;
+                serde_json::to_writer(&mut out, &summary)?;
+                writeln!(out, "}}")?;
             }
         }
-        FieldGroup::Genre => {
-            for entry in query.iter_mut() {
-                entry.duration = None;
-                entry.title = None;
-                entry.song_id = None;
-                entry.album = None;
-                entry.artist = None;
-                entry.artist_id = None;
-                entry.times_listened = None;
+        OutputFormat::Csv => print_delimited(storage, &command, b',', &mut out).await?,
+        OutputFormat::Tsv => print_delimited(storage, &command, b'\t', &mut out).await?,
+        OutputFormat::Table => {
+            // Ids mean little to people reading a table
+            let columns: Vec<_> = if command.columns.is_empty() {
+                columns(&command)
+                    .into_iter()
+                    .filter(|x| !x.ends_with("_id"))
+                    .collect()
+            } else {
+                command.columns.clone()
+            };
+            let color = match command.color {
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+                ColorChoice::Auto => is_terminal && std::env::var_os("NO_COLOR").is_none(),
+            };
+            // Column widths depend on every row
+            write_table(
+                &mut out,
+                &labelled_stats(storage, &command).await?,
+                &columns,
+                terminal_size().map(|(Width(x), _)| x.into()),
+                color,
+                durations,
+            )?;
+            if let Some(summary) = summary {
+                writeln!(out, "\n{}", summary.text(durations))?;
+            }
+        }
+        OutputFormat::Text => {
+            storage
+                .for_each_stat(&command, |mut row| {
+                    label_period(&mut row, &command, false);
+                    Ok(writeln!(out, "{}", row.text(durations))?)
+                })
+                .await?;
+            if let Some(summary) = summary {
+                writeln!(out, "\n{}", summary.text(durations))?;
             }
         }
-        FieldGroup::Title => (),
     }
+    out.flush()?;
+    Ok(())
+}
 
-    if command.json {
-        // This is safe because I copied most of the logic from serde_json
-        println!("{}", unsafe {
-            String::from_utf8_unchecked({
-                let mut buf = Vec::with_capacity(128);
-                let mut ser = Serializer::with_formatter(&mut buf, NewlineFormatter);
-                query.serialize(&mut ser)?;
-                buf
-            })
-        });
-    } else {
-        print!(
-            "{}",
-            query.iter().fold(String::new(), |acc, x| {
-                let mut acc = acc + &x.to_string();
-                acc.push('\n');
-                acc
-            })
-        );
+/// Draw the stats as a chart that fits the terminal
+async fn print_chart<S: Storage>(
+    storage: &S,
+    command: &PrintArgs,
+    style: ChartStyle,
+    durations: DurationFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    let width = terminal_size().map_or(80, |(Width(x), _)| x.into());
+    let charset = match command.ascii {
+        true => Charset::Ascii,
+        false => Charset::detect(),
+    };
+    let all_time = matches!(command.time_group, Some(TimeGroup::AllTime));
+    match style {
+        ChartStyle::Bars => write_bars(
+            out,
+            &labelled_stats(storage, command).await?,
+            !all_time,
+            width,
+            durations,
+            charset,
+        )?,
+        ChartStyle::Sparklines => {
+            if all_time {
+                return Err(anyhow!(
+                    "Sparklines need a --time-group to show the listening time over"
+                ));
+            }
+            // Each line needs every time group in order, and the limit picks lines instead
+            let mut command = command.clone();
+            command.sort.insert(
+                0,
+                SortKey {
+                    by: SortBy::Period,
+                    descending: false,
+                },
+            );
+            command.reverse = false;
+            let entries = command.limit.take().unwrap_or(10) as usize;
+            command.top_per_group = None;
+            write_sparklines(
+                out,
+                &storage.stats(&command).await?,
+                (
+                    command
+                        .time_group
+                        .as_ref()
+                        .expect("Default value set by clap"),
+                    command.weeks.unwrap_or_default(),
+                ),
+                entries,
+                width,
+                durations,
+                charset,
+            )?
+        }
+    }
+    Ok(())
+}
+
+/// Replace the time bucket key the storage returns with a label, `long` ones are meant for
+/// tables and charts
+fn label_period(row: &mut DataRow, command: &PrintArgs, long: bool) {
+    let group = command
+        .time_group
+        .as_ref()
+        .expect("Default value set by clap");
+    if matches!(group, TimeGroup::AllTime) {
+        return;
+    }
+    if let Some(label) = group.label(&row.date, command.weeks.unwrap_or_default(), long) {
+        row.date = label;
     }
+}
+
+/// Rows for tables and charts, with long labels for their time groups
+async fn labelled_stats<S: Storage>(storage: &S, command: &PrintArgs) -> Result<Vec<DataRow>> {
+    let mut rows = storage.stats(command).await?;
+    for row in &mut rows {
+        label_period(row, command, true);
+    }
+    Ok(rows)
+}
+
+/// Columns to print by default, leaving out the ones that are only known when grouping by a
+/// field that wasn't grouped by and the cumulative share unless it was asked for
+fn columns(command: &PrintArgs) -> Vec<&'static str> {
+    DataRow::COLUMNS
+        .iter()
+        .copied()
+        .filter(|column| {
+            let grouped =
+                |groups: &[FieldGroup]| groups.iter().any(|x| x.columns().contains(column));
+            !grouped(FieldGroup::value_variants()) || grouped(&command.field_group)
+        })
+        .filter(|column| *column != "cumulative_share" || command.cumulative)
+        .collect()
+}
+
+/// Print the rows as CSV or TSV with a header
+async fn print_delimited<S: Storage>(
+    storage: &S,
+    command: &PrintArgs,
+    delimiter: u8,
+    out: impl Write + Send,
+) -> Result<()> {
+    let columns = if command.columns.is_empty() {
+        columns(command)
+    } else {
+        command.columns.clone()
+    };
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(out);
+    writer.write_record(&columns)?;
+    // Times stay in seconds unless another format was asked for
+    let value = |row: &DataRow, column: &str| match (column, command.duration_format) {
+        ("time", Some(durations)) => durations.format(row.time.into()),
+        _ => row.value(column),
+    };
+    storage
+        .for_each_stat(command, |mut row| {
+            label_period(&mut row, command, false);
+            Ok(writer.write_record(columns.iter().map(|x| value(&row, x)))?)
+        })
+        .await?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Draw a calendar of the listening time of every day
+pub async fn heatmap<S: Storage>(
+    storage: &S,
+    command: HeatmapArgs,
+    week_start: Weekday,
+) -> Result<()> {
+    let range = command.range(chrono::Local::now().date_naive())?;
+    let charset = match command.ascii {
+        true => Charset::Ascii,
+        false => Charset::detect(),
+    };
+    let mut out = std::io::stdout().lock();
+    write_heatmap(
+        &mut out,
+        &storage.daily_totals(&command.filters).await?,
+        range,
+        week_start,
+        charset,
+    )?;
+    Ok(())
+}
+
+/// Show the most listened entries of two date ranges side by side
+pub async fn compare<S: Storage>(
+    storage: &S,
+    command: CompareArgs,
+    week_start: Weekday,
+) -> Result<()> {
+    let (current, previous) = command.ranges(chrono::Local::now().date_naive(), week_start)?;
+    // Every entry of both ranges is fetched so that the ranks of the ones that moved a lot are
+    // known too
+    let stats = |range| {
+        PrintArgs::new(
+            command.filters.with_range(range),
+            command.field_group.clone(),
+            vec![SortKey {
+                by: SortBy::Time,
+                descending: true,
+            }],
+        )
+    };
+    let charset = match command.ascii {
+        true => Charset::Ascii,
+        false => Charset::detect(),
+    };
+    let mut out = std::io::stdout().lock();
+    writeln!(
+        out,
+        "{} to {} compared with {} to {}\n",
+        current.0, current.1, previous.0, previous.1
+    )?;
+    write_comparison(
+        &mut out,
+        &storage.stats(&stats(current)).await?,
+        &storage.stats(&stats(previous)).await?,
+        command.limit as usize,
+        command.duration_format,
+        charset,
+    )?;
     Ok(())
 }
 